@@ -1,10 +1,20 @@
 use criterion::{criterion_group, criterion_main, Criterion};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
 
-use rust_solver::{load_precomputed, run_game};
+use rust_solver::game::State;
+use rust_solver::precompute::tables;
+use rust_solver::rules::GameRules;
+use rust_solver::run_game;
 
 fn criterion_benchmark(c: &mut Criterion) {
-    let precomputed = load_precomputed();
-    c.bench_function("run_game", |b| b.iter(|| run_game(&precomputed)));
+    // Build the move tables outside the measured loop
+    tables();
+    let rules = GameRules::default();
+    let mut rng = StdRng::seed_from_u64(0);
+    c.bench_function("run_game", |b| {
+        b.iter(|| run_game::<State, _>(&rules, &mut rng))
+    });
 }
 
 criterion_group!(benches, criterion_benchmark);
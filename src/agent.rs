@@ -0,0 +1,272 @@
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use rand::Rng;
+
+use super::board::Board;
+use super::book::OpeningBook;
+use super::error::SolverError;
+use super::expectimax::{get_expectimax_move, get_timed_expectimax_move};
+use super::game::Direction;
+use super::precompute::TranspositionTable;
+use super::random::get_random_move;
+use super::rules::GameRules;
+use super::session::Game;
+use super::transposition::{Transposition, TranspositionStats};
+
+/*
+AGENTS
+*/
+
+// A move-selection strategy. moves comes from Board::get_possible_moves, the
+// legal moves first, padded with Direction::Invalid.
+pub trait Agent<B: Board> {
+    fn choose_move(
+        &mut self,
+        state: B,
+        moves: [(Direction, B); 4],
+    ) -> Result<(Direction, B), SolverError>;
+}
+
+// Picks uniformly among the legal moves, with its own rng so the spawns of the
+// game are unaffected by the player
+pub struct RandomAgent<R: Rng> {
+    rng: R,
+}
+
+impl<R: Rng> RandomAgent<R> {
+    pub fn new(rng: R) -> RandomAgent<R> {
+        RandomAgent { rng }
+    }
+}
+
+impl<B: Board, R: Rng> Agent<B> for RandomAgent<R> {
+    fn choose_move(
+        &mut self,
+        state: B,
+        moves: [(Direction, B); 4],
+    ) -> Result<(Direction, B), SolverError> {
+        if moves[0].0 == Direction::Invalid {
+            return Err(SolverError::NoValidMove);
+        }
+        Ok(get_random_move(state, moves, &mut self.rng))
+    }
+}
+
+pub struct ExpectimaxAgent<B: Board, T: Transposition<B> = TranspositionTable<B>> {
+    // The depth of every search, or the deepest with a time budget
+    depth: u16,
+    budget: Option<Duration>,
+    // Sum of the depths searched and number of searches
+    depth_total: u64,
+    searches: u64,
+    rules: GameRules,
+    transposition: T,
+    book: Option<OpeningBook<B>>,
+    board: PhantomData<B>,
+}
+
+impl<B: Board> ExpectimaxAgent<B> {
+    // Not symmetric: canonical boards cannot use the incremental Zobrist key
+    // and cost more than their extra hits save
+    pub fn new(depth: u16, rules: &GameRules) -> ExpectimaxAgent<B> {
+        ExpectimaxAgent::with_table(depth, rules, TranspositionTable::new())
+    }
+}
+
+impl<B: Board, T: Transposition<B>> ExpectimaxAgent<B, T> {
+    // Searches with any transposition table, e.g. a FixedTranspositionTable
+    // to bound memory
+    pub fn with_table(depth: u16, rules: &GameRules, transposition: T) -> ExpectimaxAgent<B, T> {
+        ExpectimaxAgent {
+            depth,
+            budget: None,
+            depth_total: 0,
+            searches: 0,
+            rules: rules.clone(),
+            transposition,
+            book: None,
+            board: PhantomData,
+        }
+    }
+
+    // Positions in the book are played without searching
+    pub fn set_book(&mut self, book: OpeningBook<B>) -> Result<(), SolverError> {
+        if *book.rules() != self.rules {
+            return Err(SolverError::InvalidBook(
+                "built with different game rules".to_string(),
+            ));
+        }
+        self.book = Some(book);
+        Ok(())
+    }
+
+    // Deepens each search until budget runs out, see get_timed_expectimax_move
+    pub fn set_time_budget(&mut self, budget: Duration) {
+        self.budget = Some(budget);
+    }
+
+    // Average depth of the searches so far, including book moves
+    pub fn mean_depth(&self) -> f64 {
+        match self.searches {
+            0 => 0.0,
+            searches => self.depth_total as f64 / searches as f64,
+        }
+    }
+
+    pub fn transposition(&self) -> &T {
+        &self.transposition
+    }
+
+    // Accumulated over every move since the agent was created
+    pub fn stats(&self) -> TranspositionStats {
+        self.transposition.stats()
+    }
+}
+
+impl<B: Board, T: Transposition<B>> Agent<B> for ExpectimaxAgent<B, T> {
+    fn choose_move(
+        &mut self,
+        state: B,
+        moves: [(Direction, B); 4],
+    ) -> Result<(Direction, B), SolverError> {
+        let (found, depth) = match self.budget {
+            Some(budget) => get_timed_expectimax_move(
+                state,
+                moves,
+                budget,
+                self.depth,
+                &self.rules,
+                &mut self.transposition,
+                self.book.as_ref(),
+            )?,
+            None => (
+                get_expectimax_move(
+                    state,
+                    moves,
+                    self.depth,
+                    &self.rules,
+                    &mut self.transposition,
+                    self.book.as_ref(),
+                )?,
+                self.depth,
+            ),
+        };
+        self.depth_total += depth as u64;
+        self.searches += 1;
+        Ok(found)
+    }
+}
+
+/*
+GAME RUNNER
+*/
+
+// Plays a whole game with any agent, calling on_move after every ply
+pub fn play_game<B, A, R, F>(
+    agent: &mut A,
+    rules: &GameRules,
+    rng: &mut R,
+    mut on_move: F,
+) -> Result<Game<B>, SolverError>
+where
+    B: Board,
+    A: Agent<B> + ?Sized,
+    R: Rng + ?Sized,
+    F: FnMut(&Game<B>),
+{
+    let mut game: Game<B> = Game::start(rules, rng);
+    let mut moves = game.state().get_possible_moves();
+    while moves[0].0 != Direction::Invalid {
+        let (direction, _) = agent.choose_move(game.state(), moves)?;
+        if !game.play_random(direction, rules, rng) {
+            return Err(SolverError::IllegalMove(direction));
+        }
+        on_move(&game);
+        moves = game.state().get_possible_moves();
+    }
+    Ok(game)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::Grid;
+    use crate::game::State;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_random_agent() {
+        let rules = &GameRules::default();
+        let rng = &mut StdRng::seed_from_u64(0);
+        let agent = &mut RandomAgent::new(StdRng::seed_from_u64(1));
+
+        let mut plies = 0;
+        let game: Game<State> = play_game(agent, rules, rng, |_| plies += 1).unwrap();
+        assert!(game.is_over());
+        assert_eq!(game.move_count(), plies);
+        assert!(plies > 0);
+    }
+
+    #[test]
+    fn test_expectimax_agent() {
+        let rules = &GameRules::default();
+        let rng = &mut StdRng::seed_from_u64(0);
+        let agent: &mut dyn Agent<Grid<3>> = &mut ExpectimaxAgent::new(1, rules);
+
+        let game = play_game(agent, rules, rng, |_| {}).unwrap();
+        assert!(game.is_over());
+        assert!(game.score() > 0);
+    }
+
+    #[test]
+    fn test_timed_agent() {
+        let rules = &GameRules::default();
+        let rng = &mut StdRng::seed_from_u64(0);
+        let agent: &mut ExpectimaxAgent<Grid<3>> = &mut ExpectimaxAgent::new(4, rules);
+        agent.set_time_budget(Duration::from_millis(1));
+
+        let game = play_game(agent, rules, rng, |_| {}).unwrap();
+        assert!(game.is_over());
+        assert!(agent.mean_depth() >= 1.0 && agent.mean_depth() <= 4.0);
+    }
+
+    #[test]
+    fn test_expectimax_stats() {
+        let rules = &GameRules::default();
+        let rng = &mut StdRng::seed_from_u64(0);
+        let agent: &mut ExpectimaxAgent<Grid<3>> = &mut ExpectimaxAgent::new(2, rules);
+
+        let mut plies = 0;
+        play_game(agent, rules, rng, |_| plies += 1).unwrap();
+        // Counters are kept across moves rather than reset by each search
+        let stats = agent.stats();
+        assert!(stats.lookups() > plies);
+        assert!(stats.hits > 0);
+        assert_eq!(stats.inserts, stats.inserts_by_depth.iter().sum::<u64>());
+        assert!(stats.peak_size >= stats.size);
+    }
+
+    #[test]
+    fn test_illegal_move() {
+        struct StubbornAgent;
+        impl<B: Board> Agent<B> for StubbornAgent {
+            fn choose_move(
+                &mut self,
+                state: B,
+                _moves: [(Direction, B); 4],
+            ) -> Result<(Direction, B), SolverError> {
+                Ok((Direction::Invalid, state))
+            }
+        }
+
+        let rules = &GameRules::default();
+        let rng = &mut StdRng::seed_from_u64(0);
+        let result: Result<Game<Grid<3>>, _> = play_game(&mut StubbornAgent, rules, rng, |_| {});
+        assert!(matches!(
+            result,
+            Err(SolverError::IllegalMove(Direction::Invalid))
+        ));
+    }
+}
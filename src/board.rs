@@ -0,0 +1,615 @@
+use std::fmt::Debug;
+use std::hash::Hash;
+
+use rand::Rng;
+
+use super::events::MoveResult;
+use super::game::{
+    merge_line, trace_line, Direction, State, Symmetry, WideState, MAX_EXPONENT, MAX_WIDE_EXPONENT,
+};
+use super::heuristic::board_heuristic;
+use super::precompute::{
+    get_heuristic, get_move_score, get_possible_moves, get_possible_wide_moves,
+    get_wide_move_score, move_state_with_events, move_wide_state_with_events,
+};
+use super::rules::GameRules;
+use super::zobrist::{cell_key, state_zobrist, state_zobrist_after};
+
+/*
+BOARD TRAIT
+*/
+
+// Anything the game loop, random player and expectimax can play on.
+// NOTE: Values are exponents and boards are indexed by (x, y) as in State.
+pub trait Board: Copy + Eq + Ord + Hash + Debug {
+    // Width and height of the board
+    const SIZE: u16;
+
+    // Largest exponent a cell can hold
+    const MAX_EXPONENT: u16;
+
+    fn empty() -> Self;
+
+    fn index(&self, x: u16, y: u16) -> u16;
+
+    fn set_tile(&mut self, x: u16, y: u16, value: u16);
+
+    // Same convention as precompute::get_possible_moves, padded with Invalid
+    fn get_possible_moves(&self) -> [(Direction, Self); 4];
+
+    // Points earned by merges when moving in direction, 0 for Invalid
+    fn get_move_score(&self, direction: Direction) -> u64;
+
+    // Full description of a move for animation and statistics
+    fn move_with_events(&self, direction: Direction) -> MoveResult<Self>;
+
+    // Leaf evaluation for search, see heuristic::board_heuristic
+    fn heuristic(&self) -> f32 {
+        board_heuristic(self)
+    }
+
+    // Zobrist key of the board, see zobrist.rs
+    fn zobrist(&self) -> u64 {
+        let mut key = 0;
+        for y in 0..Self::SIZE {
+            for x in 0..Self::SIZE {
+                key ^= cell_key((y * Self::SIZE + x) as usize, self.index(x, y));
+            }
+        }
+        key
+    }
+
+    // Key of next_state from this board's key, for boards that can update it
+    // incrementally
+    fn zobrist_after(&self, _key: u64, next_state: &Self) -> u64 {
+        next_state.zobrist()
+    }
+
+    fn get_empty_tiles(&self) -> Vec<(u16, u16)> {
+        let mut empty_tiles = Vec::new();
+        for y in 0..Self::SIZE {
+            for x in 0..Self::SIZE {
+                if self.index(x, y) == 0 {
+                    empty_tiles.push((x, y));
+                }
+            }
+        }
+        empty_tiles
+    }
+
+    fn max_exponent(&self) -> u16 {
+        let mut max_exponent = 0;
+        for y in 0..Self::SIZE {
+            for x in 0..Self::SIZE {
+                max_exponent = max_exponent.max(self.index(x, y));
+            }
+        }
+        max_exponent
+    }
+
+    fn transform(&self, symmetry: Symmetry) -> Self {
+        let mut new_state = Self::empty();
+        for y in 0..Self::SIZE {
+            for x in 0..Self::SIZE {
+                let (tx, ty) = symmetry.map_cell(x, y, Self::SIZE);
+                new_state.set_tile(tx, ty, self.index(x, y));
+            }
+        }
+        new_state
+    }
+
+    // Smallest of the 8 symmetric boards, and the symmetry that produces it
+    fn canonical(&self) -> (Self, Symmetry) {
+        Symmetry::ALL
+            .iter()
+            .map(|symmetry| (self.transform(*symmetry), *symmetry))
+            .min_by_key(|(state, _)| *state)
+            .unwrap()
+    }
+
+    // Picks the cell and value of the next tile, None if the board is full
+    fn random_spawn<R: Rng + ?Sized>(
+        &self,
+        rules: &GameRules,
+        rng: &mut R,
+    ) -> Option<((u16, u16), u16)> {
+        let empty_tiles = self.get_empty_tiles();
+        if empty_tiles.is_empty() {
+            return None;
+        }
+        let index = rng.gen_range(0..empty_tiles.len());
+        let value = rules.sample_spawn(rng);
+        Some((empty_tiles[index], value))
+    }
+
+    fn add_random_tile<R: Rng + ?Sized>(&self, rules: &GameRules, rng: &mut R) -> Self {
+        let mut new_state = *self;
+        if let Some(((x, y), value)) = self.random_spawn(rules, rng) {
+            new_state.set_tile(x, y, value);
+        }
+        new_state
+    }
+
+    fn new<R: Rng + ?Sized>(rules: &GameRules, rng: &mut R) -> Self {
+        let mut state = Self::empty();
+        for _ in 0..rules.initial_tiles() {
+            state = state.add_random_tile(rules, rng);
+        }
+        state
+    }
+
+    // Estimate that assumes every tile was built from 2s, so it overcounts once
+    // a 4 spawns. Game loops track the exact score with get_move_score.
+    fn get_score(&self) -> u64 {
+        let mut score: u64 = 0;
+        for y in 0..Self::SIZE {
+            for x in 0..Self::SIZE {
+                let value = self.index(x, y);
+                if value > 0 {
+                    let tile = ((value - 1) as u64).saturating_mul(2u64.pow(value as u32));
+                    score = score.saturating_add(tile);
+                }
+            }
+        }
+        score
+    }
+
+    fn print_board(&self) {
+        let line = |left: &str, middle: &str, right: &str| {
+            let cells = vec!["───────"; Self::SIZE as usize];
+            format!("{}{}{}", left, cells.join(middle), right)
+        };
+        println!("{}", line("┌", "┬", "┐"));
+        for y in 0..Self::SIZE {
+            print!("│");
+            for x in 0..Self::SIZE {
+                let value = self.index(x, y);
+                // print num or . padded with tab
+                if value == 0 {
+                    print!("       │");
+                } else {
+                    print!(" {:5} │", 2u64.pow(value as u32));
+                }
+            }
+            println!();
+            if y < Self::SIZE - 1 {
+                println!("{}", line("├", "┼", "┤"));
+            } else {
+                println!("{}", line("└", "┴", "┘"));
+            }
+        }
+    }
+}
+
+/*
+PACKED 4x4 BOARDS
+*/
+
+impl Board for State {
+    const SIZE: u16 = 4;
+
+    const MAX_EXPONENT: u16 = MAX_EXPONENT;
+
+    fn empty() -> State {
+        State { board: 0 }
+    }
+
+    fn index(&self, x: u16, y: u16) -> u16 {
+        State::index(self, x, y)
+    }
+
+    fn set_tile(&mut self, x: u16, y: u16, value: u16) {
+        State::set_tile(self, x, y, value)
+    }
+
+    fn get_possible_moves(&self) -> [(Direction, State); 4] {
+        get_possible_moves(*self)
+    }
+
+    fn get_move_score(&self, direction: Direction) -> u64 {
+        get_move_score(*self, direction)
+    }
+
+    fn move_with_events(&self, direction: Direction) -> MoveResult<State> {
+        move_state_with_events(*self, direction)
+    }
+
+    fn heuristic(&self) -> f32 {
+        get_heuristic(*self)
+    }
+
+    fn zobrist(&self) -> u64 {
+        state_zobrist(*self)
+    }
+
+    fn zobrist_after(&self, key: u64, next_state: &State) -> u64 {
+        state_zobrist_after(key, *self, *next_state)
+    }
+
+    fn get_empty_tiles(&self) -> Vec<(u16, u16)> {
+        State::get_empty_tiles(self)
+    }
+
+    fn max_exponent(&self) -> u16 {
+        State::max_exponent(self)
+    }
+
+    fn transform(&self, symmetry: Symmetry) -> State {
+        State::transform(self, symmetry)
+    }
+}
+
+impl Board for WideState {
+    const SIZE: u16 = 4;
+
+    const MAX_EXPONENT: u16 = MAX_WIDE_EXPONENT;
+
+    fn empty() -> WideState {
+        WideState { board: 0 }
+    }
+
+    fn index(&self, x: u16, y: u16) -> u16 {
+        WideState::index(self, x, y)
+    }
+
+    fn set_tile(&mut self, x: u16, y: u16, value: u16) {
+        WideState::set_tile(self, x, y, value)
+    }
+
+    fn get_possible_moves(&self) -> [(Direction, WideState); 4] {
+        get_possible_wide_moves(*self)
+    }
+
+    fn get_move_score(&self, direction: Direction) -> u64 {
+        get_wide_move_score(*self, direction)
+    }
+
+    fn move_with_events(&self, direction: Direction) -> MoveResult<WideState> {
+        move_wide_state_with_events(*self, direction)
+    }
+
+    fn get_empty_tiles(&self) -> Vec<(u16, u16)> {
+        WideState::get_empty_tiles(self)
+    }
+
+    fn max_exponent(&self) -> u16 {
+        WideState::max_exponent(self)
+    }
+}
+
+/*
+GENERIC N x N BOARDS
+*/
+
+// Largest exponent a Grid cell can hold. Scores are u64, so a merge into
+// 2^63 is the largest that still has a score.
+pub const MAX_GRID_EXPONENT: u16 = 63;
+
+// NOTE: Must index cells[y][x]. Moves are merged line by line without tables,
+// so any size works, but State is much faster for 4x4.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Grid<const N: usize> {
+    pub cells: [[u8; N]; N],
+}
+
+impl<const N: usize> Grid<N> {
+    pub fn from_grid(grid: [[u16; N]; N]) -> Grid<N> {
+        let mut state = Grid::empty();
+        for (y, row) in grid.iter().enumerate() {
+            for (x, value) in row.iter().enumerate() {
+                state.set_tile(x as u16, y as u16, *value);
+            }
+        }
+        state
+    }
+
+    pub fn to_grid(&self) -> [[u16; N]; N] {
+        let mut grid = [[0; N]; N];
+        for (y, row) in grid.iter_mut().enumerate() {
+            for (x, value) in row.iter_mut().enumerate() {
+                *value = self.cells[y][x] as u16;
+            }
+        }
+        grid
+    }
+
+    // Cell k of line i, where k = 0 is the edge tiles slide towards
+    fn line_cell(direction: Direction, i: usize, k: usize) -> (usize, usize) {
+        match direction {
+            Direction::Left => (k, i),
+            Direction::Right => (N - 1 - k, i),
+            Direction::Up => (i, k),
+            Direction::Down => (i, N - 1 - k),
+            Direction::Invalid => unreachable!(),
+        }
+    }
+
+    // Returns the moved board and the points earned by merges
+    pub fn move_with_score(&self, direction: Direction) -> (Grid<N>, u64) {
+        let mut new_state = *self;
+        let mut score: u64 = 0;
+        let mut line = [0; N];
+        for i in 0..N {
+            for (k, value) in line.iter_mut().enumerate() {
+                let (x, y) = Self::line_cell(direction, i, k);
+                *value = self.cells[y][x] as u16;
+            }
+            score = score.saturating_add(merge_line(&mut line, MAX_GRID_EXPONENT));
+            for (k, value) in line.iter().enumerate() {
+                let (x, y) = Self::line_cell(direction, i, k);
+                new_state.cells[y][x] = *value as u8;
+            }
+        }
+        (new_state, score)
+    }
+
+    pub fn move_state(&self, direction: Direction) -> Grid<N> {
+        self.move_with_score(direction).0
+    }
+}
+
+impl<const N: usize> Board for Grid<N> {
+    const SIZE: u16 = N as u16;
+
+    const MAX_EXPONENT: u16 = MAX_GRID_EXPONENT;
+
+    fn empty() -> Grid<N> {
+        Grid { cells: [[0; N]; N] }
+    }
+
+    fn index(&self, x: u16, y: u16) -> u16 {
+        self.cells[y as usize][x as usize] as u16
+    }
+
+    fn set_tile(&mut self, x: u16, y: u16, value: u16) {
+        debug_assert!(
+            value <= Self::MAX_EXPONENT,
+            "tile 2^{} does not fit a cell",
+            value
+        );
+        self.cells[y as usize][x as usize] = value as u8;
+    }
+
+    fn get_possible_moves(&self) -> [(Direction, Grid<N>); 4] {
+        let mut moves = [(Direction::Invalid, *self); 4];
+        let mut index = 0;
+        for direction in [
+            Direction::Left,
+            Direction::Right,
+            Direction::Up,
+            Direction::Down,
+        ] {
+            let next_state = self.move_state(direction);
+            if next_state != *self {
+                moves[index] = (direction, next_state);
+                index += 1;
+            }
+        }
+        moves
+    }
+
+    fn get_move_score(&self, direction: Direction) -> u64 {
+        match direction {
+            Direction::Invalid => 0,
+            _ => self.move_with_score(direction).1,
+        }
+    }
+
+    fn move_with_events(&self, direction: Direction) -> MoveResult<Grid<N>> {
+        if direction == Direction::Invalid {
+            return MoveResult::new(direction, *self);
+        }
+        let mut result = MoveResult::new(direction, self.move_state(direction));
+        let mut line = [0; N];
+        let mut destinations = [0; N];
+        for i in 0..N {
+            for (k, value) in line.iter_mut().enumerate() {
+                let (x, y) = Self::line_cell(direction, i, k);
+                *value = self.cells[y][x] as u16;
+            }
+            let before = line;
+            trace_line(&mut line, MAX_GRID_EXPONENT, |from, to| {
+                destinations[from] = to as u16
+            });
+            let cell = |k: usize| {
+                let (x, y) = Self::line_cell(direction, i, k);
+                (x as u16, y as u16)
+            };
+            result.add_line(cell, &before, &destinations, &line);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::precompute::move_state;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "does not fit a cell")]
+    fn test_grid_tile_too_large() {
+        Grid::<3>::from_grid([[0, 0, 0], [0, MAX_GRID_EXPONENT + 1, 0], [0, 0, 0]]);
+    }
+
+    #[test]
+    fn test_grid_moves() {
+        let state = Grid::<3>::from_grid([[1, 1, 2], [0, 2, 2], [3, 0, 3]]);
+        assert_eq!(
+            state.move_state(Direction::Left),
+            Grid::from_grid([[2, 2, 0], [3, 0, 0], [4, 0, 0]])
+        );
+        assert_eq!(
+            state.move_state(Direction::Right),
+            Grid::from_grid([[0, 2, 2], [0, 0, 3], [0, 0, 4]])
+        );
+        assert_eq!(
+            state.move_state(Direction::Up),
+            Grid::from_grid([[1, 1, 3], [3, 2, 3], [0, 0, 0]])
+        );
+        assert_eq!(
+            state.move_state(Direction::Down),
+            Grid::from_grid([[0, 0, 0], [1, 1, 3], [3, 2, 3]])
+        );
+
+        let state = Grid::<5>::from_grid([
+            [1, 1, 1, 1, 1],
+            [0, 0, 0, 0, 0],
+            [0, 0, 0, 0, 0],
+            [0, 0, 0, 0, 0],
+            [0, 0, 0, 0, 0],
+        ]);
+        let moves = state.get_possible_moves();
+        assert_eq!(moves[0].1.cells[0], [2, 2, 1, 0, 0]);
+        assert_eq!(moves[1].1.cells[0], [0, 0, 1, 2, 2]);
+        assert_eq!(moves[2].0, Direction::Down);
+        assert_eq!(moves[3].0, Direction::Invalid);
+    }
+
+    #[test]
+    fn test_grid_matches_state() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let rules = GameRules::default();
+        let mut state = State::new(&rules, &mut rng);
+        let mut grid = Grid::<4>::from_grid(state.to_grid());
+        for _ in 0..200 {
+            let moves = state.get_possible_moves();
+            let grid_moves = grid.get_possible_moves();
+            for ((direction, next_state), (grid_direction, next_grid)) in
+                moves.iter().zip(grid_moves.iter())
+            {
+                assert_eq!(direction, grid_direction);
+                assert_eq!(next_state.to_grid(), next_grid.to_grid());
+            }
+            if moves[0].0 == Direction::Invalid {
+                break;
+            }
+            state = moves[0].1.add_random_tile(&rules, &mut rng);
+            grid = Grid::from_grid(state.to_grid());
+        }
+    }
+
+    #[test]
+    fn test_move_score() {
+        // With only 2s spawning, the exact score matches the estimate
+        let mut rng = StdRng::seed_from_u64(0);
+        let rules = GameRules::new(vec![(1, 1.0)], 2, 11).unwrap();
+        let mut state = State::new(&rules, &mut rng);
+        let mut grid = Grid::<4>::from_grid(state.to_grid());
+        let mut score = 0;
+        let mut grid_score = 0;
+        let mut moves = state.get_possible_moves();
+        while moves[0].0 != Direction::Invalid {
+            let (direction, next_state) = moves[rng.gen_range(0..4)];
+            score += state.get_move_score(direction);
+            grid_score += grid.get_move_score(direction);
+            state = next_state.add_random_tile(&rules, &mut rng);
+            grid = Grid::from_grid(state.to_grid());
+            moves = state.get_possible_moves();
+        }
+        assert!(score > 0);
+        assert_eq!(score, grid_score);
+        assert_eq!(score, state.get_score());
+
+        // A spawned 4 was never merged, so the estimate overcounts it
+        let state = Grid::<3>::from_grid([[2, 2, 0], [0, 0, 0], [0, 0, 0]]);
+        assert_eq!(state.get_move_score(Direction::Left), 8);
+        assert_eq!(state.move_state(Direction::Left).get_score(), 16);
+
+        // Tiles at the cap do not merge, and scores too large for u64 saturate
+        let state = Grid::<2>::from_grid([[63, 63], [0, 0]]);
+        assert_eq!(state.get_move_score(Direction::Left), 0);
+        assert_eq!(state.move_with_events(Direction::Left).score, 0);
+        assert_eq!(state.get_score(), u64::MAX);
+        let state = Grid::<2>::from_grid([[62, 62], [62, 62]]);
+        assert_eq!(state.get_move_score(Direction::Left), u64::MAX);
+        assert_eq!(state.move_with_events(Direction::Left).score, u64::MAX);
+        assert_eq!(state.to_string(), format!("{0} {0}/{0} {0}", 1u64 << 62));
+    }
+
+    #[test]
+    fn test_move_with_events() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let rules = GameRules::default();
+        let mut state = State::new(&rules, &mut rng);
+        for _ in 0..200 {
+            let grid = Grid::<4>::from_grid(state.to_grid());
+            for direction in [
+                Direction::Left,
+                Direction::Right,
+                Direction::Up,
+                Direction::Down,
+            ] {
+                let result = state.move_with_events(direction);
+                let grid_result = grid.move_with_events(direction);
+                assert_eq!(result.state.to_grid(), grid_result.state.to_grid());
+                assert_eq!(result.score, grid_result.score);
+                assert_eq!(result.score, state.get_move_score(direction));
+                // Events are ordered differently for right and down moves
+                assert_eq!(result.slides.len(), grid_result.slides.len());
+                assert_eq!(result.merges.len(), grid_result.merges.len());
+                for slide in &result.slides {
+                    assert!(grid_result.slides.contains(slide));
+                }
+                for merge in &result.merges {
+                    assert!(grid_result.merges.iter().any(|other| other.to == merge.to
+                        && other.value == merge.value
+                        && other.from.contains(&merge.from[0])
+                        && other.from.contains(&merge.from[1])));
+                }
+            }
+            let moves = state.get_possible_moves();
+            if moves[0].0 == Direction::Invalid {
+                break;
+            }
+            state = moves[rng.gen_range(0..4)]
+                .1
+                .add_random_tile(&rules, &mut rng);
+        }
+    }
+
+    #[test]
+    fn test_symmetry() {
+        let state = State::from_grid([[1, 1, 0, 2], [0, 3, 0, 3], [2, 0, 0, 0], [4, 0, 1, 1]]);
+        let grid = Grid::<4>::from_grid(state.to_grid());
+        let (canonical, _) = state.canonical();
+        for symmetry in Symmetry::ALL {
+            let transformed = state.transform(symmetry);
+            assert_eq!(transformed.to_grid(), grid.transform(symmetry).to_grid());
+            assert_eq!(transformed.canonical().0, canonical);
+
+            let (canonical, to_canonical) = transformed.canonical();
+            assert_eq!(transformed.transform(to_canonical), canonical);
+
+            for direction in [
+                Direction::Left,
+                Direction::Right,
+                Direction::Up,
+                Direction::Down,
+            ] {
+                let moved = move_state(state, direction);
+                let mapped = symmetry.map_direction(direction);
+                assert_eq!(move_state(transformed, mapped), moved.transform(symmetry));
+                assert_eq!(
+                    grid.transform(symmetry).move_state(mapped),
+                    grid.move_state(direction).transform(symmetry)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_board_defaults() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let rules = GameRules::default();
+        let state = Grid::<6>::new(&rules, &mut rng);
+        assert_eq!(state.get_empty_tiles().len(), 34);
+        assert!(state.max_exponent() == 1 || state.max_exponent() == 2);
+
+        let state = Grid::<3>::from_grid([[1, 2, 3], [0, 0, 0], [0, 0, 11]]);
+        assert_eq!(state.get_score(), 4 + 2 * 8 + 10 * 2048);
+    }
+}
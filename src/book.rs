@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::fs;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use super::board::Board;
+use super::error::SolverError;
+use super::expectimax::get_expectimax_move;
+use super::game::{Direction, State};
+use super::precompute::TranspositionTable;
+use super::rules::GameRules;
+use super::session::Game;
+use super::storage::{data_path, write_atomic};
+use super::transposition::transposition_key;
+use super::zobrist::ZobristBuildHasher;
+
+/*
+OPENING BOOK
+*/
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct BookEntry {
+    depth: u16,
+    direction: Direction,
+}
+
+// Best moves from deep searches of early-game positions, looked up before
+// searching. Keyed like a symmetric transposition table, so mirrored
+// openings share an entry.
+// NOTE: Keys are Zobrist keys, changing zobrist::cell_key invalidates saved books
+#[derive(Serialize, Deserialize)]
+pub struct OpeningBook<B: Board = State> {
+    size: u16,
+    rules: GameRules,
+    entries: HashMap<u64, BookEntry, ZobristBuildHasher>,
+    board: PhantomData<B>,
+}
+
+impl<B: Board> OpeningBook<B> {
+    pub fn new(rules: &GameRules) -> OpeningBook<B> {
+        OpeningBook {
+            size: B::SIZE,
+            rules: rules.clone(),
+            entries: HashMap::default(),
+            board: PhantomData,
+        }
+    }
+
+    pub fn rules(&self) -> &GameRules {
+        &self.rules
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Best move for state if the book searched it at least depth deep
+    pub fn get(&self, state: &B, depth: u16) -> Option<Direction> {
+        let (key, symmetry) = transposition_key(state, state.zobrist(), true);
+        match self.entries.get(&key) {
+            Some(entry) if entry.depth >= depth => {
+                Some(symmetry.inverse().map_direction(entry.direction))
+            }
+            _ => None,
+        }
+    }
+
+    // Keeps whichever result was searched deeper
+    pub fn insert(&mut self, state: &B, depth: u16, direction: Direction) {
+        let (key, symmetry) = transposition_key(state, state.zobrist(), true);
+        let entry = BookEntry {
+            depth,
+            direction: symmetry.map_direction(direction),
+        };
+        match self.entries.get(&key) {
+            Some(old) if old.depth > depth => {}
+            _ => {
+                self.entries.insert(key, entry);
+            }
+        }
+    }
+
+    // Plays games at depth and stores the move chosen for every position in
+    // their first plies. Positions already in the book at depth are not
+    // searched again, so harvesting can be resumed on a loaded book.
+    pub fn harvest<R: Rng + ?Sized>(
+        &mut self,
+        games: usize,
+        depth: u16,
+        plies: usize,
+        rng: &mut R,
+    ) -> Result<(), SolverError> {
+        let rules = self.rules.clone();
+        let transposition = &mut TranspositionTable::new();
+        for _ in 0..games {
+            let mut game: Game<B> = Game::start(&rules, rng);
+            while game.move_count() < plies {
+                let state = game.state();
+                let moves = state.get_possible_moves();
+                if moves[0].0 == Direction::Invalid {
+                    break;
+                }
+                let direction = match self.get(&state, depth) {
+                    Some(direction) => direction,
+                    None => {
+                        let (direction, _) =
+                            get_expectimax_move(state, moves, depth, &rules, transposition, None)?;
+                        self.insert(&state, depth, direction);
+                        direction
+                    }
+                };
+                if !game.play_random(direction, &rules, rng) {
+                    return Err(SolverError::IllegalMove(direction));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /* SAVING AND LOADING */
+
+    pub fn save(&self, path: &Path) -> Result<(), SolverError> {
+        write_atomic(path, &serde_json::to_vec(self)?)
+    }
+
+    pub fn load(path: &Path) -> Result<OpeningBook<B>, SolverError> {
+        let book: OpeningBook<B> = serde_json::from_slice(&fs::read(path)?)?;
+        if book.size != B::SIZE {
+            return Err(SolverError::InvalidBook(format!(
+                "built for {0}x{0} boards, not {1}x{1}",
+                book.size,
+                B::SIZE
+            )));
+        }
+        Ok(book)
+    }
+}
+
+// Default location of the book for B, see storage::data_dir
+pub fn book_path<B: Board>() -> PathBuf {
+    data_path(&format!("opening_book_{0}x{0}.json", B::SIZE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::Grid;
+    use crate::game::Symmetry;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_get_insert() {
+        let book: &mut OpeningBook = &mut OpeningBook::new(&GameRules::default());
+        let state = State::from_grid([[1, 2, 0, 0], [0, 0, 0, 0], [0, 0, 0, 3], [0, 0, 0, 0]]);
+        book.insert(&state, 4, Direction::Left);
+        assert_eq!(book.get(&state, 4), Some(Direction::Left));
+        assert_eq!(book.get(&state, 3), Some(Direction::Left));
+        assert_eq!(book.get(&state, 5), None);
+        // Mirrored positions share the entry, with the move mapped back
+        let flipped = state.transform(Symmetry::FlipHorizontal);
+        assert_eq!(book.get(&flipped, 4), Some(Direction::Right));
+        // Shallower results never replace deeper ones
+        book.insert(&state, 2, Direction::Up);
+        assert_eq!(book.get(&state, 4), Some(Direction::Left));
+        book.insert(&flipped, 5, Direction::Left);
+        assert_eq!(book.get(&state, 5), Some(Direction::Right));
+        assert_eq!(book.len(), 1);
+    }
+
+    #[test]
+    fn test_harvest_save_load() {
+        let rules = &GameRules::default();
+        let book: &mut OpeningBook<Grid<3>> = &mut OpeningBook::new(rules);
+        book.harvest(2, 2, 10, &mut StdRng::seed_from_u64(0))
+            .unwrap();
+        assert!(!book.is_empty() && book.len() <= 20);
+
+        let dir = std::env::temp_dir().join(format!("rust_solver_book_{}", std::process::id()));
+        let path = dir.join("book.json");
+        book.save(&path).unwrap();
+        let loaded: OpeningBook<Grid<3>> = OpeningBook::load(&path).unwrap();
+        assert_eq!(loaded.len(), book.len());
+        assert_eq!(loaded.rules(), rules);
+        assert!(matches!(
+            OpeningBook::<State>::load(&path),
+            Err(SolverError::InvalidBook(_))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
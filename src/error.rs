@@ -0,0 +1,63 @@
+use std::error::Error;
+use std::fmt;
+
+use super::game::Direction;
+
+/*
+ERRORS
+*/
+
+#[derive(Debug)]
+pub enum SolverError {
+    // Reading or writing a file failed
+    Io(std::io::Error),
+    // Saved data could not be encoded or decoded
+    Serde(serde_json::Error),
+    // The opening book was built for another board size or other rules
+    InvalidBook(String),
+    // A command line or API argument could not be used
+    InvalidArgument { name: String, value: String },
+    // The board has no legal move, the game is over
+    NoValidMove,
+    // The search picked a direction that is not among the given moves
+    IllegalMove(Direction),
+}
+
+impl fmt::Display for SolverError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SolverError::Io(err) => write!(f, "I/O error: {}", err),
+            SolverError::Serde(err) => write!(f, "invalid saved data: {}", err),
+            SolverError::InvalidBook(reason) => write!(f, "invalid opening book: {}", reason),
+            SolverError::InvalidArgument { name, value } => {
+                write!(f, "invalid {}: '{}'", name, value)
+            }
+            SolverError::NoValidMove => write!(f, "no valid move, the game is over"),
+            SolverError::IllegalMove(direction) => {
+                write!(f, "{:?} is not a possible move", direction)
+            }
+        }
+    }
+}
+
+impl Error for SolverError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SolverError::Io(err) => Some(err),
+            SolverError::Serde(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for SolverError {
+    fn from(err: std::io::Error) -> SolverError {
+        SolverError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for SolverError {
+    fn from(err: serde_json::Error) -> SolverError {
+        SolverError::Serde(err)
+    }
+}
@@ -0,0 +1,84 @@
+use serde::{Deserialize, Serialize};
+
+use super::game::Direction;
+
+/*
+MOVE EVENTS
+*/
+
+// NOTE: Cells are (x, y) and values are exponents, as everywhere else
+
+// A tile that changed cell without merging
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Slide {
+    pub from: (u16, u16),
+    pub to: (u16, u16),
+    pub value: u16,
+}
+
+// Two tiles that combined into a single tile of the next value
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Merge {
+    pub from: [(u16, u16); 2],
+    pub to: (u16, u16),
+    pub value: u16,
+    pub score: u64,
+}
+
+// Everything that happened during one move, before the next tile spawns.
+// Tiles that appear in neither slides nor merges stayed where they were.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MoveResult<B> {
+    pub direction: Direction,
+    pub state: B,
+    pub slides: Vec<Slide>,
+    pub merges: Vec<Merge>,
+    pub score: u64,
+}
+
+impl<B> MoveResult<B> {
+    pub fn new(direction: Direction, state: B) -> MoveResult<B> {
+        MoveResult {
+            direction,
+            state,
+            slides: Vec::new(),
+            merges: Vec::new(),
+            score: 0,
+        }
+    }
+
+    // Records one line of the move. cell(k) is the board cell at position k of
+    // the line, destinations[k] is where the tile at k went, before and after
+    // are the line values around the move.
+    pub fn add_line<F: Fn(usize) -> (u16, u16)>(
+        &mut self,
+        cell: F,
+        before: &[u16],
+        destinations: &[u16],
+        after: &[u16],
+    ) {
+        for (to, &value) in after.iter().enumerate() {
+            let sources: Vec<usize> = (0..before.len())
+                .filter(|&from| before[from] != 0 && destinations[from] as usize == to)
+                .collect();
+            match sources[..] {
+                [from] if from != to => self.slides.push(Slide {
+                    from: cell(from),
+                    to: cell(to),
+                    value: before[from],
+                }),
+                [first, second] => {
+                    let score = 1 << value;
+                    self.merges.push(Merge {
+                        from: [cell(first), cell(second)],
+                        to: cell(to),
+                        value,
+                        score,
+                    });
+                    self.score = self.score.saturating_add(score);
+                }
+                _ => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,350 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{self, Write as _};
+use std::path::{Path, PathBuf};
+
+use super::board::Board;
+use super::error::SolverError;
+use super::game::Direction;
+use super::notation::to_hex;
+use super::rules::GameRules;
+use super::storage::{data_path, write_atomic_with};
+
+/*
+EXACT SOLVER
+*/
+
+// NOTE: Solves a small board completely. Every position reachable from a
+// starting board is stored with the move that maximises the expected final
+// score. Positions are stored canonically, since symmetric boards have the
+// same value with the move mapped.
+
+#[derive(Debug, Clone, Copy)]
+struct Solved {
+    direction: Direction,
+    // Expected score still to come with optimal play
+    value: f64,
+    // Probability of passing through the position, see tile_probabilities
+    reach: f64,
+}
+
+pub struct ExactSolution<B: Board> {
+    rules: GameRules,
+    // Canonical starting boards and their probabilities
+    starts: Vec<(B, f64)>,
+    positions: HashMap<B, Solved>,
+    // Probability of the game ending with each tile as the largest
+    final_tiles: Vec<f64>,
+}
+
+impl<B: Board> ExactSolution<B> {
+    pub fn solve(rules: &GameRules) -> ExactSolution<B> {
+        let mut starts = vec![(B::empty(), 1.0)];
+        for _ in 0..rules.initial_tiles() {
+            starts = merge_canonical(
+                starts
+                    .iter()
+                    .flat_map(|&(state, prob)| spawns(state, rules, prob))
+                    .collect(),
+            );
+        }
+        let mut solution = ExactSolution {
+            rules: rules.clone(),
+            starts,
+            positions: HashMap::new(),
+            final_tiles: Vec::new(),
+        };
+        solution.explore();
+        // Every turn adds a tile, so sorting by tile sum puts every position
+        // before its children
+        let mut order: Vec<B> = solution.positions.keys().copied().collect();
+        order.sort_by_key(tile_sum);
+        solution.evaluate(&order);
+        solution.propagate(&order);
+        solution
+    }
+
+    // Adds every position reachable from a starting board, unsolved. Uses an
+    // explicit stack, as games on larger boards run too deep to recurse.
+    fn explore(&mut self) {
+        let mut stack: Vec<B> = self.starts.iter().map(|&(state, _)| state).collect();
+        while let Some(state) = stack.pop() {
+            if self.positions.contains_key(&state) {
+                continue;
+            }
+            self.positions.insert(
+                state,
+                Solved {
+                    direction: Direction::Invalid,
+                    value: 0.0,
+                    reach: 0.0,
+                },
+            );
+            for (direction, next_state) in state.get_possible_moves() {
+                if direction == Direction::Invalid {
+                    continue;
+                }
+                for (child, _) in spawns(next_state, &self.rules, 1.0) {
+                    if !self.positions.contains_key(&child) {
+                        stack.push(child);
+                    }
+                }
+            }
+        }
+    }
+
+    // Finds the best move and expected score of each position, visiting them
+    // in reverse so that all their children are solved first
+    fn evaluate(&mut self, order: &[B]) {
+        for &state in order.iter().rev() {
+            let mut best = (Direction::Invalid, 0.0);
+            for (direction, next_state) in state.get_possible_moves() {
+                if direction == Direction::Invalid {
+                    continue;
+                }
+                let mut value = state.get_move_score(direction) as f64;
+                for (child, prob) in spawns(next_state, &self.rules, 1.0) {
+                    value += prob * self.positions[&child].value;
+                }
+                if best.0 == Direction::Invalid || value > best.1 {
+                    best = (direction, value);
+                }
+            }
+            let solved = self.positions.get_mut(&state).unwrap();
+            solved.direction = best.0;
+            solved.value = best.1;
+        }
+    }
+
+    // Pushes the starting probabilities through the optimal policy, visiting
+    // positions after all their parents
+    fn propagate(&mut self, order: &[B]) {
+        for &(state, prob) in &self.starts {
+            self.positions.get_mut(&state).unwrap().reach += prob;
+        }
+        let max_exponent = order.iter().map(|state| state.max_exponent()).max();
+        self.final_tiles = vec![0.0; max_exponent.map_or(0, |exponent| exponent as usize + 1)];
+        for &state in order {
+            let solved = self.positions[&state];
+            if solved.direction == Direction::Invalid {
+                self.final_tiles[state.max_exponent() as usize] += solved.reach;
+                continue;
+            }
+            let (_, next_state) = state
+                .get_possible_moves()
+                .into_iter()
+                .find(|(direction, _)| *direction == solved.direction)
+                .unwrap();
+            for (child, prob) in spawns(next_state, &self.rules, solved.reach) {
+                self.positions.get_mut(&child).unwrap().reach += prob;
+            }
+        }
+        while self.final_tiles.last() == Some(&0.0) {
+            self.final_tiles.pop();
+        }
+    }
+
+    // Number of canonical positions reachable with optimal play or otherwise
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+
+    // Optimal move and expected score still to come, None if state cannot be
+    // reached from a starting board
+    pub fn get(&self, state: &B) -> Option<(Direction, f64)> {
+        let (canonical, symmetry) = state.canonical();
+        let solved = self.positions.get(&canonical)?;
+        Some((
+            symmetry.inverse().map_direction(solved.direction),
+            solved.value,
+        ))
+    }
+
+    // Expected final score of a game played optimally from the start
+    pub fn expected_score(&self) -> f64 {
+        self.starts
+            .iter()
+            .map(|(state, prob)| prob * self.positions[state].value)
+            .sum()
+    }
+
+    // Probability of reaching each tile, indexed by exponent
+    pub fn tile_probabilities(&self) -> Vec<f64> {
+        let mut probs = self.final_tiles.clone();
+        for i in (0..probs.len().saturating_sub(1)).rev() {
+            probs[i] += probs[i + 1];
+        }
+        probs
+    }
+
+    /* TABLE OUTPUT */
+
+    // Expected score and tile probabilities as a two column table
+    pub fn summary(&self) -> String {
+        let mut text = format!("{0}x{0} board, {1} positions\n", B::SIZE, self.len());
+        writeln!(text, "expected score\t{:.4}", self.expected_score()).unwrap();
+        for (exponent, prob) in self.tile_probabilities().iter().enumerate().skip(1) {
+            writeln!(text, "P(tile >= {})\t{:.6}", 1u64 << exponent, prob).unwrap();
+        }
+        text
+    }
+
+    // One line per canonical position, sorted: board in hex notation, optimal
+    // move, expected score still to come and probability of reaching it
+    pub fn write_table(&self, path: &Path) -> Result<(), SolverError> {
+        let mut states: Vec<&B> = self.positions.keys().collect();
+        states.sort();
+        write_atomic_with(path, |file| {
+            writeln!(file, "board,move,value,reach")?;
+            for state in states {
+                let solved = &self.positions[state];
+                let hex = to_hex(state).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "tile too large for hex")
+                })?;
+                writeln!(
+                    file,
+                    "{},{:?},{},{}",
+                    hex, solved.direction, solved.value, solved.reach
+                )?;
+            }
+            Ok(())
+        })
+    }
+}
+
+// Default location of the position table for B, see storage::data_dir
+pub fn exact_table_path<B: Board>() -> PathBuf {
+    data_path(&format!("exact_{0}x{0}.csv", B::SIZE))
+}
+
+// Canonical boards after a tile spawns in state, with their probabilities
+// scaled by prob. Spawn weights are normalised, as f32 weights rarely add up
+// to exactly 1.
+fn spawns<B: Board>(state: B, rules: &GameRules, prob: f64) -> Vec<(B, f64)> {
+    let empty_tiles = state.get_empty_tiles();
+    let total: f64 = rules
+        .spawns()
+        .iter()
+        .map(|&(_, spawn_prob)| spawn_prob as f64)
+        .sum();
+    let frac = prob / (empty_tiles.len() as f64 * total);
+    let mut children = Vec::with_capacity(empty_tiles.len() * rules.spawns().len());
+    for (x, y) in empty_tiles {
+        for &(value, spawn_prob) in rules.spawns() {
+            let mut child = state;
+            child.set_tile(x, y, value);
+            children.push((child.canonical().0, frac * spawn_prob as f64));
+        }
+    }
+    merge_canonical(children)
+}
+
+fn merge_canonical<B: Board>(mut boards: Vec<(B, f64)>) -> Vec<(B, f64)> {
+    boards.sort_by_key(|(state, _)| *state);
+    boards.dedup_by(|(state, prob), (kept, kept_prob)| {
+        let same = state == kept;
+        if same {
+            *kept_prob += *prob;
+        }
+        same
+    });
+    boards
+}
+
+fn tile_sum<B: Board>(state: &B) -> u64 {
+    let mut sum = 0;
+    for y in 0..B::SIZE {
+        for x in 0..B::SIZE {
+            match state.index(x, y) {
+                0 => {}
+                value => sum += 1 << value,
+            }
+        }
+    }
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::Grid;
+    use crate::game::Symmetry;
+    use crate::session::Game;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::fs;
+
+    #[test]
+    fn test_solve_2x2() {
+        let rules = &GameRules::default();
+        let solution: ExactSolution<Grid<2>> = ExactSolution::solve(rules);
+        let probs = solution.tile_probabilities();
+        assert!((probs[1] - 1.0).abs() < 1e-9);
+        assert!(probs.windows(2).all(|pair| pair[0] >= pair[1]));
+        // 32 is the largest tile that fits on a 2x2 board
+        assert_eq!(probs.len(), 6);
+        assert!(probs[5] > 0.0);
+
+        // Playing the policy scores the expected score on average
+        let rng = &mut StdRng::seed_from_u64(0);
+        let games = 20000;
+        let mut total = 0;
+        for _ in 0..games {
+            let mut game: Game<Grid<2>> = Game::start(rules, rng);
+            while let Some((direction, _)) = solution.get(&game.state()) {
+                if !game.play_random(direction, rules, rng) {
+                    break;
+                }
+            }
+            assert!(game.is_over());
+            total += game.score();
+        }
+        let mean = total as f64 / games as f64;
+        assert!((mean - solution.expected_score()).abs() < 1.0);
+    }
+
+    #[test]
+    fn test_symmetry() {
+        let solution: ExactSolution<Grid<2>> = ExactSolution::solve(&GameRules::default());
+        let state = Grid::from_grid([[1, 0], [2, 0]]);
+        let (direction, value) = solution.get(&state).unwrap();
+        for symmetry in Symmetry::ALL {
+            let transformed = state.transform(symmetry);
+            assert_eq!(
+                solution.get(&transformed),
+                Some((symmetry.map_direction(direction), value))
+            );
+        }
+        assert_eq!(solution.get(&Grid::from_grid([[5, 5], [5, 5]])), None);
+    }
+
+    #[test]
+    fn test_write_table() {
+        let solution: ExactSolution<Grid<2>> = ExactSolution::solve(&GameRules::default());
+        let dir = std::env::temp_dir().join(format!("rust_solver_exact_{}", std::process::id()));
+        let path = dir.join("exact.csv");
+        solution.write_table(&path).unwrap();
+        let table = fs::read_to_string(&path).unwrap();
+        assert_eq!(table.lines().count(), solution.len() + 1);
+        assert!(table.starts_with("board,move,value,reach\n"));
+        assert!(table.lines().all(|line| line.split(',').count() == 4));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Takes several minutes and a few GB of memory, run with --ignored
+    #[test]
+    #[ignore]
+    fn test_solve_3x3() {
+        let solution: ExactSolution<Grid<3>> = ExactSolution::solve(&GameRules::default());
+        println!("{}", solution.summary());
+        assert_eq!(solution.len(), 48_713_519);
+        assert!((solution.expected_score() - 5468.4868).abs() < 1e-3);
+        let probs = solution.tile_probabilities();
+        assert!((probs[1] - 1.0).abs() < 1e-9);
+        assert!(probs[probs.len() - 1] > 0.0);
+    }
+}
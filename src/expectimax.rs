@@ -1,171 +1,363 @@
-use std::cmp;
-
-use super::game::{Direction, State};
-use super::precompute::{get_possible_moves, Precomputed, TranspositionTable};
-
-// TODO: Precompute this
-fn pow_grid(state: &State) -> [[u64; 4]; 4] {
-    [
-        [
-            2u64.pow(state.grid[0][0] as u32),
-            2u64.pow(state.grid[0][1] as u32),
-            2u64.pow(state.grid[0][2] as u32),
-            2u64.pow(state.grid[0][3] as u32),
-        ],
-        [
-            2u64.pow(state.grid[1][0] as u32),
-            2u64.pow(state.grid[1][1] as u32),
-            2u64.pow(state.grid[1][2] as u32),
-            2u64.pow(state.grid[1][3] as u32),
-        ],
-        [
-            2u64.pow(state.grid[2][0] as u32),
-            2u64.pow(state.grid[2][1] as u32),
-            2u64.pow(state.grid[2][2] as u32),
-            2u64.pow(state.grid[2][3] as u32),
-        ],
-        [
-            2u64.pow(state.grid[3][0] as u32),
-            2u64.pow(state.grid[3][1] as u32),
-            2u64.pow(state.grid[3][2] as u32),
-            2u64.pow(state.grid[3][3] as u32),
-        ],
-    ]
-}
+use std::time::{Duration, Instant};
+
+use super::board::Board;
+use super::book::OpeningBook;
+use super::error::SolverError;
+use super::game::Direction;
+use super::heuristic::{heuristic_base, heuristic_floor};
+use super::rules::GameRules;
+use super::transposition::Transposition;
+use super::zobrist::zobrist_spawn;
 
-fn corner_heuristic(state: State) -> u64 {
-    let pow_grid = pow_grid(&state);
-
-    let lower_left = 10 * pow_grid[3][0]
-        + 5 * pow_grid[2][0]
-        + 2 * pow_grid[1][0]
-        + pow_grid[0][0]
-        + 5 * pow_grid[3][1]
-        + 2 * pow_grid[2][1]
-        + pow_grid[1][1]
-        + 2 * pow_grid[3][2]
-        + pow_grid[2][2]
-        + pow_grid[3][3];
-    let lower_right = 10 * pow_grid[3][3]
-        + 5 * pow_grid[2][3]
-        + 2 * pow_grid[1][3]
-        + pow_grid[0][3]
-        + 5 * pow_grid[3][2]
-        + 2 * pow_grid[2][2]
-        + pow_grid[1][2]
-        + 2 * pow_grid[3][1]
-        + pow_grid[2][1]
-        + pow_grid[3][0];
-    let upper_left = 10 * pow_grid[0][0]
-        + 5 * pow_grid[1][0]
-        + 2 * pow_grid[2][0]
-        + pow_grid[3][0]
-        + 5 * pow_grid[0][1]
-        + 2 * pow_grid[1][1]
-        + pow_grid[2][1]
-        + 2 * pow_grid[0][2]
-        + pow_grid[1][2]
-        + pow_grid[0][3];
-    let upper_right = 10 * pow_grid[0][3]
-        + 5 * pow_grid[1][3]
-        + 2 * pow_grid[2][3]
-        + pow_grid[3][3]
-        + 5 * pow_grid[0][2]
-        + 2 * pow_grid[1][2]
-        + pow_grid[2][2]
-        + 2 * pow_grid[0][1]
-        + pow_grid[1][1]
-        + pow_grid[0][0];
-
-    cmp::max(
-        cmp::max(lower_left, lower_right),
-        cmp::max(upper_left, upper_right),
-    )
+// Value of a lost game, below the heuristic of any board it could have been
+pub fn lost_score<B: Board>() -> f32 {
+    heuristic_floor(B::SIZE, B::MAX_EXPONENT) - heuristic_base(B::SIZE)
 }
 
-fn _get_expectimax_move(
-    state: State,
+// key is state.zobrist(), updated incrementally down the tree. Returns None if
+// the deadline passes before the search is done. Subtrees that finished are
+// still stored, only the unfinished nodes are left out of the table.
+#[allow(clippy::too_many_arguments)]
+fn _get_expectimax_move<B: Board, T: Transposition<B> + ?Sized>(
+    state: B,
+    key: u64,
     prob: f32,
     depth: u16,
     min_prob: f32,
-    precomputed: &Precomputed,
-    transposition: &mut TranspositionTable,
-) -> (Direction, f32) {
-    let moves = get_possible_moves(state, precomputed);
+    rules: &GameRules,
+    transposition: &mut T,
+    deadline: Option<Instant>,
+) -> Option<(Direction, f32)> {
+    debug_assert_eq!(key, state.zobrist());
+    let moves = state.get_possible_moves();
     if moves[0].0 == Direction::Invalid {
-        return (Direction::Invalid, 0.0);
+        return Some((Direction::Invalid, lost_score::<B>()));
+    }
+
+    if let Some(solved) = transposition.solved(&state) {
+        return Some(solved);
     }
 
     if depth == 0 {
-        return (moves[0].0, corner_heuristic(state) as f32);
+        return Some((moves[0].0, state.heuristic()));
     }
 
-    let lookup = transposition.get(&state, depth, prob);
+    // Pruning below here depends on prob only through prob / min_prob, so the
+    // table compares that. Iterative deepening changes min_prob between
+    // searches, and an entry pruned harder must not answer a later lookup.
+    let relative_prob = prob / min_prob;
+    let lookup = transposition.get(&state, key, depth, relative_prob);
     if let Some((direction, score)) = lookup {
-        return (*direction, *score);
+        return Some((direction, score));
     }
 
-    let mut best_move = (Direction::Invalid, -1.0);
+    // Reading the clock costs far less than a subtree this deep
+    if depth >= 2 && deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+        return None;
+    }
+
+    // Heuristic values can be negative, so the first move always counts
+    let mut best_move = (Direction::Invalid, f32::NEG_INFINITY);
     for (direction, next_state) in moves {
         if direction == Direction::Invalid {
             continue;
         }
 
+        let next_key = state.zobrist_after(key, &next_state);
         let empty_tiles = next_state.get_empty_tiles();
         let frac = 1.0 / (empty_tiles.len() as f32);
         let mut next_score = 0.0;
         let mut denom = 0.0;
         for (x, y) in empty_tiles {
-            let mut temp_state = next_state;
-            temp_state.grid[y as usize][x as usize] = 1;
-            let _next_score = _get_expectimax_move(
-                temp_state,
-                prob * frac * 0.9,
-                depth - 1,
-                min_prob,
-                precomputed,
-                transposition,
-            );
-            next_score += frac * 0.9 * _next_score.1;
-            denom += frac * 0.9;
-
-            if prob * frac * 0.1 > min_prob {
+            // Always expand the most likely spawn, prune unlikely ones
+            for (i, &(value, spawn_prob)) in rules.spawns().iter().enumerate() {
+                if i > 0 && prob * frac * spawn_prob <= min_prob {
+                    continue;
+                }
                 let mut temp_state = next_state;
-                temp_state.grid[y as usize][x as usize] = 2;
+                temp_state.set_tile(x, y, value);
                 let _next_score = _get_expectimax_move(
                     temp_state,
-                    prob * frac * 0.1,
+                    zobrist_spawn::<B>(next_key, x, y, value),
+                    prob * frac * spawn_prob,
                     depth - 1,
                     min_prob,
-                    precomputed,
+                    rules,
                     transposition,
-                );
-                next_score += frac * 0.1 * _next_score.1;
-                denom += frac * 0.1;
+                    deadline,
+                )?;
+                next_score += frac * spawn_prob * _next_score.1;
+                denom += frac * spawn_prob;
             }
         }
         next_score /= denom;
-        if next_score > best_move.1 {
+        if best_move.0 == Direction::Invalid || next_score > best_move.1 {
             best_move = (direction, next_score);
         }
     }
 
-    transposition.insert(state, depth, prob, best_move);
+    transposition.insert(state, key, depth, relative_prob, best_move);
+
+    Some(best_move)
+}
+
+// Subtrees less likely than this are cut short, halving with each ply
+fn min_prob(depth: u16) -> f32 {
+    0.1 / 2f32.powi(depth as i32 + 4)
+}
+
+// Move of a full search at depth, None if the deadline passed first
+fn _search<B: Board, T: Transposition<B> + ?Sized>(
+    state: B,
+    moves: [(Direction, B); 4],
+    depth: u16,
+    rules: &GameRules,
+    transposition: &mut T,
+    deadline: Option<Instant>,
+) -> Option<Result<(Direction, B), SolverError>> {
+    let (direction, _) = _get_expectimax_move(
+        state,
+        state.zobrist(),
+        1.0,
+        depth,
+        min_prob(depth),
+        rules,
+        transposition,
+        deadline,
+    )?;
+    Some(_find_move(moves, direction))
+}
+
+fn _find_move<B: Board>(
+    moves: [(Direction, B); 4],
+    direction: Direction,
+) -> Result<(Direction, B), SolverError> {
+    match moves.iter().find(|(dir, _)| *dir == direction) {
+        Some(&(direction, next_state)) if direction != Direction::Invalid => {
+            Ok((direction, next_state))
+        }
+        _ => Err(SolverError::IllegalMove(direction)),
+    }
+}
 
-    best_move
+// A book move searched at least as deep saves the search
+fn _book_move<B: Board>(
+    state: B,
+    moves: [(Direction, B); 4],
+    depth: u16,
+    book: Option<&OpeningBook<B>>,
+) -> Option<(Direction, B)> {
+    let direction = book?.get(&state, depth)?;
+    _find_move(moves, direction).ok()
 }
 
-pub fn get_expectimax_move(
-    state: State,
-    moves: [(Direction, State); 4],
+pub fn get_expectimax_move<B: Board, T: Transposition<B> + ?Sized>(
+    state: B,
+    moves: [(Direction, B); 4],
     depth: u16,
-    precomputed: &Precomputed,
-    transposition: &mut TranspositionTable,
-) -> (Direction, State) {
-    let min_prob = 0.1 / ((1 << (depth + 4)) as f32);
-    let (direction, _) =
-        _get_expectimax_move(state, 1.0, depth, min_prob, precomputed, transposition);
-    let next_state = moves.iter().find(|(dir, _)| *dir == direction).unwrap().1;
-    transposition.clear();
-    (direction, next_state)
+    rules: &GameRules,
+    transposition: &mut T,
+    book: Option<&OpeningBook<B>>,
+) -> Result<(Direction, B), SolverError> {
+    if moves[0].0 == Direction::Invalid {
+        return Err(SolverError::NoValidMove);
+    }
+    if let Some(found) = _book_move(state, moves, depth, book) {
+        return Ok(found);
+    }
+    // Without a deadline the search always finishes
+    let result = _search(state, moves, depth, rules, transposition, None).unwrap();
+    transposition.next_generation();
+    result
+}
+
+// Searches at depth 1, 2, 3 and so on until the budget runs out or max_depth
+// is done, and plays the move of the deepest search that finished, which is
+// returned with the move. Depth 1 always finishes. The searches share the
+// transposition table, so each one reuses the results of the last.
+pub fn get_timed_expectimax_move<B: Board, T: Transposition<B> + ?Sized>(
+    state: B,
+    moves: [(Direction, B); 4],
+    budget: Duration,
+    max_depth: u16,
+    rules: &GameRules,
+    transposition: &mut T,
+    book: Option<&OpeningBook<B>>,
+) -> Result<((Direction, B), u16), SolverError> {
+    if moves[0].0 == Direction::Invalid {
+        return Err(SolverError::NoValidMove);
+    }
+    if let Some(found) = _book_move(state, moves, max_depth, book) {
+        return Ok((found, max_depth));
+    }
+    let deadline = Instant::now() + budget;
+    let mut best = (
+        _search(state, moves, 1, rules, transposition, None).unwrap(),
+        1,
+    );
+    for depth in 2..=max_depth {
+        match _search(state, moves, depth, rules, transposition, Some(deadline)) {
+            Some(result) => best = (result, depth),
+            None => break,
+        }
+    }
+    transposition.next_generation();
+    let (result, depth) = best;
+    Ok((result?, depth))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::State;
+    use crate::precompute::TranspositionTable;
+    use crate::transposition::{FixedTranspositionTable, Replacement};
+
+    #[test]
+    fn test_expectimax_move() {
+        let rules = &GameRules::default();
+        let transposition = &mut TranspositionTable::new();
+
+        let state = State::from_grid([[1, 1, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0]]);
+        let moves = state.get_possible_moves();
+        let (direction, next_state) =
+            get_expectimax_move(state, moves, 2, rules, transposition, None).unwrap();
+        assert!(moves.contains(&(direction, next_state)));
+
+        let over = State::from_grid([[1, 2, 1, 2], [2, 1, 2, 1], [1, 2, 1, 2], [2, 1, 2, 1]]);
+        let moves = over.get_possible_moves();
+        let result = get_expectimax_move(over, moves, 2, rules, transposition, None);
+        assert!(matches!(result, Err(SolverError::NoValidMove)));
+    }
+
+    #[test]
+    fn test_negative_heuristic() {
+        let rules = &GameRules::default();
+        let transposition = &mut TranspositionTable::new();
+        // Late game, every move leads to a board scored below zero
+        let state = State::from_grid([[1, 8, 2, 6], [8, 3, 6, 0], [12, 14, 12, 6], [2, 3, 5, 2]]);
+        assert!(state.heuristic() < 0.0);
+        assert!(state.heuristic() > heuristic_floor(State::SIZE, State::MAX_EXPONENT));
+        let moves = state.get_possible_moves();
+        let (direction, next_state) =
+            get_expectimax_move(state, moves, 1, rules, transposition, None).unwrap();
+        assert!(moves.contains(&(direction, next_state)));
+
+        // Losing scores below any board
+        let over = State::from_grid([[1, 2, 1, 2], [2, 1, 2, 1], [1, 2, 1, 2], [2, 1, 2, 1]]);
+        let (_, value) = _get_expectimax_move(
+            over,
+            over.zobrist(),
+            1.0,
+            2,
+            0.0,
+            rules,
+            transposition,
+            None,
+        )
+        .unwrap();
+        assert!(value < heuristic_floor(State::SIZE, State::MAX_EXPONENT));
+    }
+
+    #[test]
+    fn test_min_prob() {
+        assert_eq!(min_prob(3), 0.1 / 128.0);
+        // Deep enough to overflow an integer shift
+        assert!(min_prob(40) > 0.0 && min_prob(40) < min_prob(27));
+        assert!(min_prob(u16::MAX) >= 0.0);
+    }
+
+    #[test]
+    fn test_cutoff_reuse() {
+        let rules = &GameRules::default();
+        let state = State::from_grid([[1, 2, 3, 4], [0, 1, 0, 2], [0, 0, 2, 0], [1, 0, 0, 3]]);
+        let search = |min_prob, transposition: &mut TranspositionTable<State>| {
+            let key = state.zobrist();
+            _get_expectimax_move(state, key, 1.0, 2, min_prob, rules, transposition, None)
+        };
+        // The iteration 3 cutoff expands 4s that iteration 2 pruned
+        let shallow = search(min_prob(2), &mut TranspositionTable::new());
+        let deep = search(min_prob(3), &mut TranspositionTable::new());
+        assert_ne!(shallow, deep);
+
+        // So entries from iteration 2 do not answer iteration 3
+        let transposition = &mut TranspositionTable::new();
+        assert_eq!(search(min_prob(2), transposition), shallow);
+        let hits = transposition.stats().hits;
+        assert_eq!(search(min_prob(3), transposition), deep);
+        assert_eq!(transposition.stats().hits, hits);
+        // but answer a repeat of iteration 3, and a search pruned harder
+        assert_eq!(search(min_prob(3), transposition), deep);
+        assert_eq!(search(min_prob(2), transposition), deep);
+        assert_eq!(transposition.stats().hits, hits + 2);
+    }
+
+    #[test]
+    fn test_timed_move() {
+        let rules = &GameRules::default();
+        let state = State::from_grid([[1, 2, 3, 0], [0, 1, 0, 0], [0, 0, 2, 0], [1, 0, 0, 0]]);
+        let moves = state.get_possible_moves();
+
+        let transposition = &mut TranspositionTable::new();
+        let budget = Duration::from_secs(60);
+        let (found, depth) =
+            get_timed_expectimax_move(state, moves, budget, 3, rules, transposition, None).unwrap();
+        assert!(moves.contains(&found));
+        assert_eq!(depth, 3);
+
+        // Out of time: depth 1 still finishes, depth 2 stops at its root
+        let timed = &mut TranspositionTable::new();
+        let (found, depth) =
+            get_timed_expectimax_move(state, moves, Duration::ZERO, 3, rules, timed, None).unwrap();
+        let fixed = &mut TranspositionTable::new();
+        let expected = get_expectimax_move(state, moves, 1, rules, fixed, None).unwrap();
+        assert_eq!((found, depth), (expected, 1));
+        assert_eq!(timed.stats().inserts, fixed.stats().inserts);
+    }
+
+    #[test]
+    fn test_book_move() {
+        let rules = &GameRules::default();
+        let transposition = &mut TranspositionTable::new();
+        let book = &mut OpeningBook::new(rules);
+
+        let state = State::from_grid([[1, 1, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0]]);
+        let moves = state.get_possible_moves();
+        book.insert(&state, 3, Direction::Down);
+        let result = get_expectimax_move(state, moves, 3, rules, transposition, Some(book));
+        assert_eq!(result.unwrap().0, Direction::Down);
+        assert_eq!(transposition.stats().lookups(), 0);
+        // Too shallow for the search
+        let result = get_expectimax_move(state, moves, 4, rules, transposition, Some(book));
+        assert!(result.is_ok());
+        assert!(transposition.stats().lookups() > 0);
+    }
+
+    #[test]
+    fn test_fixed_transposition() {
+        let rules = &GameRules::default();
+        let mut state = State::from_grid([[1, 2, 3, 0], [0, 1, 0, 0], [0, 0, 2, 0], [1, 0, 0, 0]]);
+        for scheme in [
+            Replacement::DepthPreferred,
+            Replacement::AlwaysReplace,
+            Replacement::TwoTier,
+        ] {
+            let map = &mut TranspositionTable::with_symmetry();
+            let fixed = &mut FixedTranspositionTable::with_symmetry(4, scheme);
+            for _ in 0..10 {
+                let moves = state.get_possible_moves();
+                let expected = get_expectimax_move(state, moves, 2, rules, map, None).unwrap();
+                let (direction, next_state) =
+                    get_expectimax_move(state, moves, 2, rules, fixed, None).unwrap();
+                assert_eq!((direction, next_state), expected);
+                state = next_state.get_empty_tiles()[0..1].iter().fold(
+                    next_state,
+                    |mut state, &(x, y)| {
+                        state.set_tile(x, y, 1);
+                        state
+                    },
+                );
+            }
+        }
+    }
 }
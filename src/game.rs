@@ -1,78 +1,137 @@
-use rand;
 use serde::{Deserialize, Serialize};
 
 /*
 ENUMS
 */
 
-// NOTE: Must index state.grid[y][x]
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+// NOTE: Each cell is a 4-bit exponent. Row y occupies bits 48 - 16 * y and up,
+// with x = 0 in the most significant nibble of the row.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct State {
-    pub grid: [[u16; 4]; 4],
+    pub board: u64,
+}
+
+// Largest exponent a 4-bit cell can hold (32768)
+pub const MAX_EXPONENT: u16 = 15;
+
+const ROW_MASK: u64 = 0xFFFF;
+const COL_MASK: u64 = 0x000F_000F_000F_000F;
+
+fn shift(x: u16, y: u16) -> u32 {
+    60 - 16 * y as u32 - 4 * x as u32
 }
 
 impl State {
+    pub fn from_grid(grid: [[u16; 4]; 4]) -> State {
+        let mut state = State { board: 0 };
+        for (y, row) in grid.iter().enumerate() {
+            for (x, value) in row.iter().enumerate() {
+                state.set_tile(x as u16, y as u16, *value);
+            }
+        }
+        state
+    }
+
+    pub fn to_grid(&self) -> [[u16; 4]; 4] {
+        let mut grid = [[0; 4]; 4];
+        for (y, row) in grid.iter_mut().enumerate() {
+            for (x, value) in row.iter_mut().enumerate() {
+                *value = self.index(x as u16, y as u16);
+            }
+        }
+        grid
+    }
+
+    pub fn from_rows(rows: [u16; 4]) -> State {
+        State {
+            board: ((rows[0] as u64) << 48)
+                | ((rows[1] as u64) << 32)
+                | ((rows[2] as u64) << 16)
+                | rows[3] as u64,
+        }
+    }
+
     pub fn index(&self, x: u16, y: u16) -> u16 {
-        self.grid[y as usize][x as usize]
-    }
-
-    pub fn row_ints(&self) -> (u16, u16, u16, u16) {
-        (
-            (self.grid[0][0] << 12)
-                | (self.grid[0][1] << 8)
-                | self.grid[0][2] << 4
-                | self.grid[0][3],
-            (self.grid[1][0] << 12)
-                | (self.grid[1][1] << 8)
-                | self.grid[1][2] << 4
-                | self.grid[1][3],
-            (self.grid[2][0] << 12)
-                | (self.grid[2][1] << 8)
-                | self.grid[2][2] << 4
-                | self.grid[2][3],
-            (self.grid[3][0] << 12)
-                | (self.grid[3][1] << 8)
-                | self.grid[3][2] << 4
-                | self.grid[3][3],
-        )
-    }
-
-    pub fn col_ints(&self) -> (u16, u16, u16, u16) {
-        (
-            (self.grid[0][0] << 12)
-                | (self.grid[1][0] << 8)
-                | self.grid[2][0] << 4
-                | self.grid[3][0],
-            (self.grid[0][1] << 12)
-                | (self.grid[1][1] << 8)
-                | self.grid[2][1] << 4
-                | self.grid[3][1],
-            (self.grid[0][2] << 12)
-                | (self.grid[1][2] << 8)
-                | self.grid[2][2] << 4
-                | self.grid[3][2],
-            (self.grid[0][3] << 12)
-                | (self.grid[1][3] << 8)
-                | self.grid[2][3] << 4
-                | self.grid[3][3],
-        )
+        ((self.board >> shift(x, y)) & 0xF) as u16
     }
 
+    pub fn set_tile(&mut self, x: u16, y: u16, value: u16) {
+        let shift = shift(x, y);
+        self.board = (self.board & !(0xF << shift)) | (((value as u64) & 0xF) << shift);
+    }
+
+    pub fn row(&self, y: u16) -> u16 {
+        ((self.board >> (48 - 16 * y as u32)) & ROW_MASK) as u16
+    }
+
+    pub fn rows(&self) -> [u16; 4] {
+        [self.row(0), self.row(1), self.row(2), self.row(3)]
+    }
+
+    pub fn col(&self, x: u16) -> u16 {
+        let col = (self.board >> (12 - 4 * x as u32)) & COL_MASK;
+        ((col >> 36) | (col >> 24) | (col >> 12) | col) as u16
+    }
+
+    pub fn cols(&self) -> [u16; 4] {
+        [self.col(0), self.col(1), self.col(2), self.col(3)]
+    }
+
+    // Swaps the 2x2 off-diagonal nibble blocks, then the 8-bit blocks
     pub fn transpose(&self) -> State {
-        let mut new_state = State { grid: [[0; 4]; 4] };
-        for y in 0..4 {
-            for x in 0..4 {
-                new_state.grid[x][y] = self.grid[y][x];
-            }
+        let x = self.board;
+        let a1 = x & 0xF0F0_0F0F_F0F0_0F0F;
+        let a2 = x & 0x0000_F0F0_0000_F0F0;
+        let a3 = x & 0x0F0F_0000_0F0F_0000;
+        let a = a1 | (a2 << 12) | (a3 >> 12);
+        let b1 = a & 0xFF00_FF00_00FF_00FF;
+        let b2 = a & 0x00FF_00FF_0000_0000;
+        let b3 = a & 0x0000_0000_FF00_FF00;
+        State {
+            board: b1 | (b2 >> 24) | (b3 << 24),
         }
-        new_state
+    }
+
+    // Mirrors left and right by swapping nibbles, then bytes, within each row
+    pub fn flip_horizontal(&self) -> State {
+        let x = self.board;
+        let x = ((x & 0xF0F0_F0F0_F0F0_F0F0) >> 4) | ((x & 0x0F0F_0F0F_0F0F_0F0F) << 4);
+        let x = ((x & 0xFF00_FF00_FF00_FF00) >> 8) | ((x & 0x00FF_00FF_00FF_00FF) << 8);
+        State { board: x }
+    }
+
+    // Mirrors top and bottom by swapping rows
+    pub fn flip_vertical(&self) -> State {
+        let x = self.board.rotate_left(32);
+        let x = ((x & 0xFFFF_0000_FFFF_0000) >> 16) | ((x & 0x0000_FFFF_0000_FFFF) << 16);
+        State { board: x }
+    }
+
+    pub fn transform(&self, symmetry: Symmetry) -> State {
+        match symmetry {
+            Symmetry::Identity => *self,
+            Symmetry::RotateCw => self.transpose().flip_horizontal(),
+            Symmetry::Rotate180 => self.flip_horizontal().flip_vertical(),
+            Symmetry::RotateCcw => self.transpose().flip_vertical(),
+            Symmetry::FlipHorizontal => self.flip_horizontal(),
+            Symmetry::FlipVertical => self.flip_vertical(),
+            Symmetry::Transpose => self.transpose(),
+            Symmetry::AntiTranspose => self.transpose().flip_horizontal().flip_vertical(),
+        }
+    }
+
+    pub fn count_empty(&self) -> u32 {
+        let mut x = self.board;
+        x |= (x >> 2) & 0x3333_3333_3333_3333;
+        x |= x >> 1;
+        (!x & 0x1111_1111_1111_1111).count_ones()
     }
 
     pub fn get_empty_tiles(&self) -> Vec<(u16, u16)> {
-        let mut empty_tiles = Vec::new();
+        let mut empty_tiles = Vec::with_capacity(self.count_empty() as usize);
         for y in 0..4 {
             for x in 0..4 {
-                if self.grid[y as usize][x as usize] == 0 {
+                if self.index(x, y) == 0 {
                     empty_tiles.push((x, y));
                 }
             }
@@ -80,61 +139,108 @@ impl State {
         empty_tiles
     }
 
-    pub fn add_random_tile(&self) -> State {
-        let empty_tiles = self.get_empty_tiles();
-        if empty_tiles.len() == 0 {
-            return *self;
-        }
-        let index = rand::random::<usize>() % empty_tiles.len();
-        let (x, y) = empty_tiles[index];
-        let value = if rand::random::<f32>() < 0.9 { 1 } else { 2 };
-        let mut new_state = *self;
-        new_state.grid[y as usize][x as usize] = value;
-        new_state
+    pub fn max_exponent(&self) -> u16 {
+        (0..16).map(|i| self.index(i % 4, i / 4)).max().unwrap()
+    }
+
+    pub fn to_wide(&self) -> WideState {
+        WideState::from_grid(self.to_grid())
     }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct WideState {
+    pub board: u128,
+}
+
+// Largest exponent a 5-bit cell can hold
+pub const MAX_WIDE_EXPONENT: u16 = 31;
 
-    pub fn new() -> State {
-        let mut state = State { grid: [[0; 4]; 4] };
-        state = state.add_random_tile();
-        state = state.add_random_tile();
+const WIDE_ROW_MASK: u128 = 0xF_FFFF;
+
+fn wide_shift(x: u16, y: u16) -> u32 {
+    75 - 20 * y as u32 - 5 * x as u32
+}
+
+impl WideState {
+    pub fn from_grid(grid: [[u16; 4]; 4]) -> WideState {
+        let mut state = WideState { board: 0 };
+        for (y, row) in grid.iter().enumerate() {
+            for (x, value) in row.iter().enumerate() {
+                state.set_tile(x as u16, y as u16, *value);
+            }
+        }
         state
     }
 
-    pub fn get_score(&self) -> u64 {
-        let mut score = 0;
+    pub fn to_grid(&self) -> [[u16; 4]; 4] {
+        let mut grid = [[0; 4]; 4];
+        for (y, row) in grid.iter_mut().enumerate() {
+            for (x, value) in row.iter_mut().enumerate() {
+                *value = self.index(x as u16, y as u16);
+            }
+        }
+        grid
+    }
+
+    pub fn from_rows(rows: [u32; 4]) -> WideState {
+        WideState {
+            board: ((rows[0] as u128) << 60)
+                | ((rows[1] as u128) << 40)
+                | ((rows[2] as u128) << 20)
+                | rows[3] as u128,
+        }
+    }
+
+    pub fn index(&self, x: u16, y: u16) -> u16 {
+        ((self.board >> wide_shift(x, y)) & 0x1F) as u16
+    }
+
+    pub fn set_tile(&mut self, x: u16, y: u16, value: u16) {
+        let shift = wide_shift(x, y);
+        self.board = (self.board & !(0x1F << shift)) | (((value as u128) & 0x1F) << shift);
+    }
+
+    pub fn row(&self, y: u16) -> u32 {
+        ((self.board >> (60 - 20 * y as u32)) & WIDE_ROW_MASK) as u32
+    }
+
+    pub fn rows(&self) -> [u32; 4] {
+        [self.row(0), self.row(1), self.row(2), self.row(3)]
+    }
+
+    pub fn transpose(&self) -> WideState {
+        let mut new_state = WideState { board: 0 };
         for y in 0..4 {
             for x in 0..4 {
-                let value = self.grid[x as usize][y as usize];
-                if value > 0 {
-                    score += ((value - 1) as u64) * 2u64.pow(value as u32);
-                }
+                new_state.set_tile(y, x, self.index(x, y));
             }
         }
-        score
+        new_state
     }
 
-    pub fn print_board(&self) {
-        println!("┌───────┬───────┬───────┬───────┐");
+    pub fn get_empty_tiles(&self) -> Vec<(u16, u16)> {
+        let mut empty_tiles = Vec::new();
         for y in 0..4 {
-            print!("│");
             for x in 0..4 {
-                let value = self.grid[x as usize][y as usize];
-                // print num or . padded with tab
-                if value == 0 {
-                    print!("       │");
-                } else {
-                    print!(" {:5} │", 2u64.pow(value as u32));
+                if self.index(x, y) == 0 {
+                    empty_tiles.push((x, y));
                 }
             }
-            println!();
-            if y < 3 {
-                println!("├───────┼───────┼───────┼───────┤");
-            } else {
-                println!("└───────┴───────┴───────┴───────┘");
-            }
         }
-        println!("Score: {}", self.get_score());
-        println!();
+        empty_tiles
+    }
+
+    pub fn max_exponent(&self) -> u16 {
+        (0..16).map(|i| self.index(i % 4, i / 4)).max().unwrap()
+    }
+
+    // None once a tile no longer fits in a 4-bit cell
+    pub fn to_narrow(&self) -> Option<State> {
+        if self.max_exponent() > MAX_EXPONENT {
+            return None;
+        }
+        Some(State::from_grid(self.to_grid()))
     }
 }
 
@@ -147,100 +253,299 @@ pub enum Direction {
     Invalid,
 }
 
+// The 8 rotations and reflections of a square board
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Symmetry {
+    Identity,
+    RotateCw,
+    Rotate180,
+    RotateCcw,
+    FlipHorizontal,
+    FlipVertical,
+    Transpose,
+    AntiTranspose,
+}
+
+impl Symmetry {
+    pub const ALL: [Symmetry; 8] = [
+        Symmetry::Identity,
+        Symmetry::RotateCw,
+        Symmetry::Rotate180,
+        Symmetry::RotateCcw,
+        Symmetry::FlipHorizontal,
+        Symmetry::FlipVertical,
+        Symmetry::Transpose,
+        Symmetry::AntiTranspose,
+    ];
+
+    // Cell that (x, y) is sent to on a size x size board
+    pub fn map_cell(&self, x: u16, y: u16, size: u16) -> (u16, u16) {
+        let last = size - 1;
+        match self {
+            Symmetry::Identity => (x, y),
+            Symmetry::RotateCw => (last - y, x),
+            Symmetry::Rotate180 => (last - x, last - y),
+            Symmetry::RotateCcw => (y, last - x),
+            Symmetry::FlipHorizontal => (last - x, y),
+            Symmetry::FlipVertical => (x, last - y),
+            Symmetry::Transpose => (y, x),
+            Symmetry::AntiTranspose => (last - y, last - x),
+        }
+    }
+
+    // Direction on the transformed board that matches direction on the
+    // original, so moving then transforming equals transforming then moving
+    pub fn map_direction(&self, direction: Direction) -> Direction {
+        // Every symmetry fixes the centre of a 3x3 board, so map a unit step
+        // away from it
+        let (x, y) = match direction {
+            Direction::Left => (0, 1),
+            Direction::Right => (2, 1),
+            Direction::Up => (1, 0),
+            Direction::Down => (1, 2),
+            Direction::Invalid => return Direction::Invalid,
+        };
+        match self.map_cell(x, y, 3) {
+            (0, 1) => Direction::Left,
+            (2, 1) => Direction::Right,
+            (1, 0) => Direction::Up,
+            (1, 2) => Direction::Down,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn inverse(&self) -> Symmetry {
+        match self {
+            Symmetry::RotateCw => Symmetry::RotateCcw,
+            Symmetry::RotateCcw => Symmetry::RotateCw,
+            _ => *self,
+        }
+    }
+}
+
 /*
-GETS PRECOMPUTED, DO NOT CALL DIRECTLY
+MOVES
 */
 
-fn merge(row: [u16; 4]) -> [u16; 4] {
-    let mut arr: Vec<u16> = Vec::new();
-    for i in 0..4 {
-        let value = row[i];
+// Slides every tile towards index 0, merging equal neighbours at most once,
+// and returns the points earned (the value of each merged tile).
+// Tiles already at max_exponent never merge, so a full-size pair stays as two
+// tiles instead of overflowing into the neighbouring cell.
+pub fn merge_line(line: &mut [u16], max_exponent: u16) -> u64 {
+    trace_line(line, max_exponent, |_, _| {})
+}
+
+// Same as merge_line, calling on_move(from, to) for every tile in the line
+pub fn trace_line<F: FnMut(usize, usize)>(
+    line: &mut [u16],
+    max_exponent: u16,
+    mut on_move: F,
+) -> u64 {
+    let mut len = 0;
+    let mut can_merge = false;
+    let mut score: u64 = 0;
+    for i in 0..line.len() {
+        let value = std::mem::take(&mut line[i]);
         if value == 0 {
             continue;
         }
-        if arr.len() > 0 && arr[arr.len() - 1] == value {
-            let last = arr.pop().unwrap();
-            arr.push(last + 1);
-            arr.push(100); // prevent double merge
+        if can_merge && value < max_exponent && line[len - 1] == value {
+            line[len - 1] += 1;
+            score = score.saturating_add(1 << line[len - 1]);
+            can_merge = false; // prevent double merge
+            on_move(i, len - 1);
         } else {
-            arr.push(value);
+            line[len] = value;
+            len += 1;
+            can_merge = true;
+            on_move(i, len - 1);
         }
     }
-    arr = arr.into_iter().filter(|x| *x != 100).collect();
-    for _ in arr.len()..4 {
-        arr.push(0);
-    }
-    [arr[0], arr[1], arr[2], arr[3]]
+    score
+}
+
+/*
+GETS PRECOMPUTED, DO NOT CALL DIRECTLY
+*/
+
+fn merge(mut row: [u16; 4], max_exponent: u16) -> [u16; 4] {
+    merge_line(&mut row, max_exponent);
+    row
+}
+
+// Does not depend on direction, runs of equal tiles merge the same way
+pub fn merge_score(mut row: [u16; 4], max_exponent: u16) -> u64 {
+    merge_line(&mut row, max_exponent)
 }
 
 pub fn reverse(row: [u16; 4]) -> [u16; 4] {
     [row[3], row[2], row[1], row[0]]
 }
 
-pub fn move_left(row: [u16; 4]) -> [u16; 4] {
-    merge(row)
+pub fn move_left(row: [u16; 4], max_exponent: u16) -> [u16; 4] {
+    merge(row, max_exponent)
+}
+
+pub fn move_right(row: [u16; 4], max_exponent: u16) -> [u16; 4] {
+    reverse(merge(reverse(row), max_exponent))
+}
+
+// Cell each tile ends up in, indexed by the cell it started in
+pub fn trace_left(row: [u16; 4], max_exponent: u16) -> [u16; 4] {
+    let mut destinations = [0, 1, 2, 3];
+    let mut line = row;
+    trace_line(&mut line, max_exponent, |from, to| {
+        destinations[from] = to as u16
+    });
+    destinations
 }
 
-pub fn move_right(row: [u16; 4]) -> [u16; 4] {
-    reverse(merge(reverse(row)))
+pub fn trace_right(row: [u16; 4], max_exponent: u16) -> [u16; 4] {
+    let destinations = trace_left(reverse(row), max_exponent);
+    reverse(destinations.map(|to| 3 - to))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::board::Board;
+    use crate::rules::GameRules;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
 
     #[test]
     fn test_get_tile() {
-        let state = State {
-            grid: [[0, 15, 14, 13], [12, 11, 10, 9], [8, 7, 6, 5], [4, 3, 2, 1]],
-        };
-        assert_eq!(state.grid[0][0], 0);
-        assert_eq!(state.grid[0][1], 15);
-        assert_eq!(state.grid[0][2], 14);
-        assert_eq!(state.grid[0][3], 13);
-        assert_eq!(state.grid[1][0], 12);
-        assert_eq!(state.grid[1][1], 11);
-        assert_eq!(state.grid[1][2], 10);
-        assert_eq!(state.grid[1][3], 9);
-        assert_eq!(state.grid[2][0], 8);
-        assert_eq!(state.grid[2][1], 7);
-        assert_eq!(state.grid[2][2], 6);
-        assert_eq!(state.grid[2][3], 5);
-        assert_eq!(state.grid[3][0], 4);
-        assert_eq!(state.grid[3][1], 3);
-        assert_eq!(state.grid[3][2], 2);
-        assert_eq!(state.grid[3][3], 1);
+        let state =
+            State::from_grid([[0, 15, 14, 13], [12, 11, 10, 9], [8, 7, 6, 5], [4, 3, 2, 1]]);
+        assert_eq!(state.index(0, 0), 0);
+        assert_eq!(state.index(1, 0), 15);
+        assert_eq!(state.index(2, 0), 14);
+        assert_eq!(state.index(3, 0), 13);
+        assert_eq!(state.index(0, 1), 12);
+        assert_eq!(state.index(1, 1), 11);
+        assert_eq!(state.index(2, 1), 10);
+        assert_eq!(state.index(3, 1), 9);
+        assert_eq!(state.index(0, 2), 8);
+        assert_eq!(state.index(1, 2), 7);
+        assert_eq!(state.index(2, 2), 6);
+        assert_eq!(state.index(3, 2), 5);
+        assert_eq!(state.index(0, 3), 4);
+        assert_eq!(state.index(1, 3), 3);
+        assert_eq!(state.index(2, 3), 2);
+        assert_eq!(state.index(3, 3), 1);
     }
 
     #[test]
     fn test_transpose() {
-        let state = State {
-            grid: [[0, 15, 14, 13], [12, 11, 10, 9], [8, 7, 6, 5], [4, 3, 2, 1]],
-        };
+        let state =
+            State::from_grid([[0, 15, 14, 13], [12, 11, 10, 9], [8, 7, 6, 5], [4, 3, 2, 1]]);
         let transposed = state.transpose();
-        assert_eq!(transposed.grid[0][0], 0);
-        assert_eq!(transposed.grid[0][1], 12);
-        assert_eq!(transposed.grid[0][2], 8);
-        assert_eq!(transposed.grid[0][3], 4);
-        assert_eq!(transposed.grid[1][0], 15);
-        assert_eq!(transposed.grid[1][1], 11);
-        assert_eq!(transposed.grid[1][2], 7);
-        assert_eq!(transposed.grid[1][3], 3);
-        assert_eq!(transposed.grid[2][0], 14);
-        assert_eq!(transposed.grid[2][1], 10);
-        assert_eq!(transposed.grid[2][2], 6);
-        assert_eq!(transposed.grid[2][3], 2);
-        assert_eq!(transposed.grid[3][0], 13);
-        assert_eq!(transposed.grid[3][1], 9);
-        assert_eq!(transposed.grid[3][2], 5);
-        assert_eq!(transposed.grid[3][3], 1);
+        assert_eq!(transposed.index(0, 0), 0);
+        assert_eq!(transposed.index(1, 0), 12);
+        assert_eq!(transposed.index(2, 0), 8);
+        assert_eq!(transposed.index(3, 0), 4);
+        assert_eq!(transposed.index(0, 1), 15);
+        assert_eq!(transposed.index(1, 1), 11);
+        assert_eq!(transposed.index(2, 1), 7);
+        assert_eq!(transposed.index(3, 1), 3);
+        assert_eq!(transposed.index(0, 2), 14);
+        assert_eq!(transposed.index(1, 2), 10);
+        assert_eq!(transposed.index(2, 2), 6);
+        assert_eq!(transposed.index(3, 2), 2);
+        assert_eq!(transposed.index(0, 3), 13);
+        assert_eq!(transposed.index(1, 3), 9);
+        assert_eq!(transposed.index(2, 3), 5);
+        assert_eq!(transposed.index(3, 3), 1);
+    }
+
+    #[test]
+    fn test_transform() {
+        let state =
+            State::from_grid([[0, 15, 14, 13], [12, 11, 10, 9], [8, 7, 6, 5], [4, 3, 2, 1]]);
+        assert_eq!(
+            state.flip_horizontal().to_grid(),
+            [[13, 14, 15, 0], [9, 10, 11, 12], [5, 6, 7, 8], [1, 2, 3, 4]]
+        );
+        assert_eq!(
+            state.flip_vertical().to_grid(),
+            [[4, 3, 2, 1], [8, 7, 6, 5], [12, 11, 10, 9], [0, 15, 14, 13]]
+        );
+        assert_eq!(
+            state.transform(Symmetry::RotateCw).to_grid(),
+            [[4, 8, 12, 0], [3, 7, 11, 15], [2, 6, 10, 14], [1, 5, 9, 13]]
+        );
+        for symmetry in Symmetry::ALL {
+            let transformed = state.transform(symmetry);
+            assert_eq!(transformed.transform(symmetry.inverse()), state);
+            for y in 0..4 {
+                for x in 0..4 {
+                    let (tx, ty) = symmetry.map_cell(x, y, 4);
+                    assert_eq!(transformed.index(tx, ty), state.index(x, y));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_map_direction() {
+        let symmetry = Symmetry::RotateCw;
+        assert_eq!(symmetry.map_direction(Direction::Left), Direction::Up);
+        assert_eq!(symmetry.map_direction(Direction::Up), Direction::Right);
+        assert_eq!(
+            Symmetry::FlipHorizontal.map_direction(Direction::Left),
+            Direction::Right
+        );
+        assert_eq!(
+            Symmetry::AntiTranspose.map_direction(Direction::Down),
+            Direction::Left
+        );
+        for symmetry in Symmetry::ALL {
+            for direction in [
+                Direction::Left,
+                Direction::Right,
+                Direction::Up,
+                Direction::Down,
+            ] {
+                let mapped = symmetry.map_direction(direction);
+                assert_eq!(symmetry.inverse().map_direction(mapped), direction);
+            }
+        }
+    }
+
+    #[test]
+    fn test_rows_and_cols() {
+        let state =
+            State::from_grid([[0, 15, 14, 13], [12, 11, 10, 9], [8, 7, 6, 5], [4, 3, 2, 1]]);
+        assert_eq!(state.board, 0x0FED_CBA9_8765_4321);
+        assert_eq!(state.rows(), [0x0FED, 0xCBA9, 0x8765, 0x4321]);
+        assert_eq!(state.cols(), [0x0C84, 0xFB73, 0xEA62, 0xD951]);
+        assert_eq!(State::from_rows(state.rows()), state);
+        assert_eq!(State::from_grid(state.to_grid()), state);
+    }
+
+    #[test]
+    fn test_set_tile() {
+        let mut state =
+            State::from_grid([[0, 15, 14, 13], [12, 11, 10, 9], [8, 7, 6, 5], [4, 3, 2, 1]]);
+        state.set_tile(1, 2, 3);
+        assert_eq!(state.index(1, 2), 3);
+        assert_eq!(state.index(0, 2), 8);
+        assert_eq!(state.index(2, 2), 6);
+        state.set_tile(0, 0, 15);
+        assert_eq!(state.row(0), 0xFFED);
+    }
+
+    #[test]
+    fn test_count_empty() {
+        let state = State::from_grid([[0, 15, 14, 13], [12, 11, 0, 9], [8, 7, 6, 5], [4, 3, 2, 1]]);
+        assert_eq!(state.count_empty(), 2);
+        assert_eq!(State { board: 0 }.count_empty(), 16);
+        assert_eq!(State { board: u64::MAX }.count_empty(), 0);
     }
 
     #[test]
     fn test_get_empty_tiles() {
-        let state = State {
-            grid: [[0, 15, 14, 13], [12, 11, 0, 9], [8, 7, 6, 5], [4, 3, 2, 1]],
-        };
+        let state = State::from_grid([[0, 15, 14, 13], [12, 11, 0, 9], [8, 7, 6, 5], [4, 3, 2, 1]]);
         let empty_tiles = state.get_empty_tiles();
         assert_eq!(empty_tiles.len(), 2);
         assert_eq!(empty_tiles[0], (0, 0));
@@ -249,76 +554,157 @@ mod tests {
 
     #[test]
     fn test_add_random_tile() {
-        let mut state = State {
-            grid: [[0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0]],
-        };
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut state = State::from_grid([[0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0]]);
         assert_eq!(state.get_empty_tiles().len(), 16);
-        state = state.add_random_tile();
+        state = state.add_random_tile(&GameRules::default(), &mut rng);
         assert_eq!(state.get_empty_tiles().len(), 15);
-        state = state.add_random_tile();
+        state = state.add_random_tile(&GameRules::default(), &mut rng);
         assert_eq!(state.get_empty_tiles().len(), 14);
     }
 
     #[test]
     fn test_get_initial_state() {
-        let state = State::new();
+        let state = State::new(&GameRules::default(), &mut StdRng::seed_from_u64(0));
         assert_eq!(state.get_empty_tiles().len(), 14);
-        let max_tile = state.grid.iter().flat_map(|row| row.iter()).max().unwrap();
-        assert_eq!(*max_tile == 1 || *max_tile == 2, true); // before pow
+        let max_tile = state.to_grid().into_iter().flatten().max().unwrap();
+        assert!(max_tile == 1 || max_tile == 2); // before pow
+    }
+
+    #[test]
+    fn test_seeded_spawns() {
+        let rules = GameRules::default();
+        let mut rng_a = StdRng::seed_from_u64(42);
+        let mut rng_b = StdRng::seed_from_u64(42);
+        let mut state_a = State::new(&rules, &mut rng_a);
+        let mut state_b = State::new(&rules, &mut rng_b);
+        assert_eq!(state_a, state_b);
+        for _ in 0..14 {
+            state_a = state_a.add_random_tile(&rules, &mut rng_a);
+            state_b = state_b.add_random_tile(&rules, &mut rng_b);
+            assert_eq!(state_a, state_b);
+        }
+        assert_eq!(state_a.count_empty(), 0);
     }
 
     #[test]
     fn test_get_score() {
-        let state = State {
-            grid: [[1, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0]],
-        };
+        let state = State::from_grid([[1, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0]]);
         assert_eq!(state.get_score(), 0);
-        let state = State {
-            grid: [[0, 1, 2, 3], [4, 5, 6, 7], [8, 9, 10, 11], [12, 13, 14, 15]],
-        };
+        let state =
+            State::from_grid([[0, 1, 2, 3], [4, 5, 6, 7], [8, 9, 10, 11], [12, 13, 14, 15]]);
         assert_eq!(state.get_score(), 851972);
     }
 
     #[test]
     fn test_merge() {
         let mut row = [0, 0, 0, 0];
-        row = merge(row);
+        row = merge(row, MAX_EXPONENT);
         assert_eq!(row, [0, 0, 0, 0]);
         row = [1, 0, 0, 0];
-        row = merge(row);
+        row = merge(row, MAX_EXPONENT);
         assert_eq!(row, [1, 0, 0, 0]);
         row = [0, 0, 1, 0];
-        row = merge(row);
+        row = merge(row, MAX_EXPONENT);
         assert_eq!(row, [1, 0, 0, 0]);
         row = [0, 2, 3, 0];
-        row = merge(row);
+        row = merge(row, MAX_EXPONENT);
         assert_eq!(row, [2, 3, 0, 0]);
         row = [0, 2, 2, 0];
-        row = merge(row);
+        row = merge(row, MAX_EXPONENT);
         assert_eq!(row, [3, 0, 0, 0]);
         row = [0, 2, 2, 2];
-        row = merge(row);
+        row = merge(row, MAX_EXPONENT);
         assert_eq!(row, [3, 2, 0, 0]);
         row = [2, 2, 2, 2];
-        row = merge(row);
+        row = merge(row, MAX_EXPONENT);
         assert_eq!(row, [3, 3, 0, 0]);
         row = [2, 3, 3, 2];
-        row = merge(row);
+        row = merge(row, MAX_EXPONENT);
         assert_eq!(row, [2, 4, 2, 0]);
         row = [2, 2, 0, 0];
-        row = merge(row);
+        row = merge(row, MAX_EXPONENT);
         assert_eq!(row, [3, 0, 0, 0]);
         row = [2, 2, 0, 2];
-        row = merge(row);
+        row = merge(row, MAX_EXPONENT);
         assert_eq!(row, [3, 2, 0, 0]);
         row = [0, 3, 2, 2];
-        row = merge(row);
+        row = merge(row, MAX_EXPONENT);
         assert_eq!(row, [3, 3, 0, 0]);
         row = [3, 3, 4, 0];
-        row = merge(row);
+        row = merge(row, MAX_EXPONENT);
         assert_eq!(row, [4, 4, 0, 0]);
     }
 
+    #[test]
+    fn test_merge_max_exponent() {
+        let mut row = [15, 15, 0, 0];
+        row = merge(row, MAX_EXPONENT);
+        assert_eq!(row, [15, 15, 0, 0]);
+        row = [14, 14, 15, 0];
+        row = merge(row, MAX_EXPONENT);
+        assert_eq!(row, [15, 15, 0, 0]);
+        row = [15, 15, 0, 0];
+        row = merge(row, MAX_WIDE_EXPONENT);
+        assert_eq!(row, [16, 0, 0, 0]);
+        row = [16, 16, 17, 0];
+        row = merge(row, MAX_WIDE_EXPONENT);
+        assert_eq!(row, [17, 17, 0, 0]);
+        row = [31, 31, 0, 0];
+        row = merge(row, MAX_WIDE_EXPONENT);
+        assert_eq!(row, [31, 31, 0, 0]);
+    }
+
+    #[test]
+    fn test_wide_state() {
+        let grid = [
+            [0, 17, 16, 13],
+            [12, 11, 10, 9],
+            [8, 7, 6, 5],
+            [4, 3, 2, 31],
+        ];
+        let state = WideState::from_grid(grid);
+        assert_eq!(state.to_grid(), grid);
+        assert_eq!(state.row(0), (17 << 10) | (16 << 5) | 13);
+        assert_eq!(WideState::from_rows(state.rows()), state);
+        assert_eq!(state.transpose().index(1, 0), 12);
+        assert_eq!(state.transpose().transpose(), state);
+        assert_eq!(state.get_empty_tiles(), vec![(0, 0)]);
+        assert_eq!(state.max_exponent(), 31);
+        assert_eq!(state.to_narrow(), None);
+
+        let narrow =
+            State::from_grid([[0, 15, 14, 13], [12, 11, 0, 9], [8, 7, 6, 5], [4, 3, 2, 1]]);
+        assert_eq!(narrow.to_wide().to_narrow(), Some(narrow));
+    }
+
+    #[test]
+    fn test_merge_score() {
+        assert_eq!(merge_score([0, 0, 0, 0], MAX_EXPONENT), 0);
+        assert_eq!(merge_score([1, 1, 0, 0], MAX_EXPONENT), 4);
+        assert_eq!(merge_score([2, 2, 2, 2], MAX_EXPONENT), 16);
+        assert_eq!(merge_score([1, 1, 2, 0], MAX_EXPONENT), 4);
+        assert_eq!(merge_score([3, 0, 3, 1], MAX_EXPONENT), 16);
+        assert_eq!(merge_score([15, 15, 0, 0], MAX_EXPONENT), 0);
+        assert_eq!(merge_score([15, 15, 0, 0], MAX_WIDE_EXPONENT), 65536);
+        assert_eq!(
+            merge_score([2, 2, 2, 0], MAX_EXPONENT),
+            merge_score(reverse([2, 2, 2, 0]), MAX_EXPONENT)
+        );
+    }
+
+    #[test]
+    fn test_trace() {
+        assert_eq!(trace_left([0, 0, 0, 0], MAX_EXPONENT), [0, 1, 2, 3]);
+        assert_eq!(trace_left([0, 1, 0, 1], MAX_EXPONENT), [0, 0, 2, 0]);
+        assert_eq!(trace_left([2, 2, 2, 2], MAX_EXPONENT), [0, 0, 1, 1]);
+        assert_eq!(trace_left([3, 2, 0, 2], MAX_EXPONENT), [0, 1, 2, 1]);
+        assert_eq!(trace_left([15, 15, 0, 1], MAX_EXPONENT), [0, 1, 2, 2]);
+        assert_eq!(trace_right([0, 1, 0, 1], MAX_EXPONENT), [0, 3, 2, 3]);
+        assert_eq!(trace_right([2, 2, 2, 2], MAX_EXPONENT), [2, 2, 3, 3]);
+        assert_eq!(trace_right([1, 0, 0, 0], MAX_EXPONENT), [3, 1, 2, 3]);
+    }
+
     #[test]
     fn test_reverse() {
         let row = [1, 2, 3, 4];
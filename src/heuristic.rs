@@ -0,0 +1,161 @@
+use super::board::Board;
+
+/*
+HEURISTIC
+*/
+
+// Leaf evaluation is a sum over every row and column of the board. Each line
+// is rewarded for empty cells and adjacent equal tiles, and penalised for
+// large tiles and for breaking monotonicity, which keeps big tiles together
+// along an edge.
+const LINE_BASE: f32 = 200000.0;
+const EMPTY_WEIGHT: f32 = 270.0;
+const MERGES_WEIGHT: f32 = 700.0;
+const MONOTONICITY_POWER: f32 = 4.0;
+const MONOTONICITY_WEIGHT: f32 = 47.0;
+const SUM_POWER: f32 = 3.5;
+const SUM_WEIGHT: f32 = 11.0;
+
+// Sum of exponent^SUM_POWER over the line, grows much slower than the tiles
+pub fn line_sum(line: &[u16]) -> f32 {
+    line.iter()
+        .map(|&value| (value as f32).powf(SUM_POWER))
+        .sum()
+}
+
+// Cost of making the line monotonic in the cheaper of the two directions
+pub fn line_monotonicity(line: &[u16]) -> f32 {
+    let mut left = 0.0;
+    let mut right = 0.0;
+    for pair in line.windows(2) {
+        let a = (pair[0] as f32).powf(MONOTONICITY_POWER);
+        let b = (pair[1] as f32).powf(MONOTONICITY_POWER);
+        if a > b {
+            left += a - b;
+        } else {
+            right += b - a;
+        }
+    }
+    f32::min(left, right)
+}
+
+pub fn line_empty(line: &[u16]) -> u8 {
+    line.iter().filter(|&&value| value == 0).count() as u8
+}
+
+// Runs of equal tiles, ignoring gaps: a run of k tiles counts k
+pub fn line_merges(line: &[u16]) -> u8 {
+    let mut merges = 0;
+    let mut run = 0;
+    let mut prev = 0;
+    for &value in line.iter().filter(|&&value| value != 0) {
+        if value == prev {
+            run += 1;
+        } else if run > 0 {
+            merges += 1 + run;
+            run = 0;
+        }
+        prev = value;
+    }
+    if run > 0 {
+        merges += 1 + run;
+    }
+    merges
+}
+
+// The components of one line, precomputed for every packed row
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LineFeatures {
+    pub sum: f32,
+    pub monotonicity: f32,
+    pub empty: u8,
+    pub merges: u8,
+}
+
+impl LineFeatures {
+    pub fn new(line: &[u16]) -> LineFeatures {
+        LineFeatures {
+            sum: line_sum(line),
+            monotonicity: line_monotonicity(line),
+            empty: line_empty(line),
+            merges: line_merges(line),
+        }
+    }
+
+    pub fn heuristic(&self) -> f32 {
+        LINE_BASE + EMPTY_WEIGHT * self.empty as f32 + MERGES_WEIGHT * self.merges as f32
+            - MONOTONICITY_WEIGHT * self.monotonicity
+            - SUM_WEIGHT * self.sum
+    }
+}
+
+// Base value of every row and column together, what board_heuristic gives a
+// board with nothing to penalise
+pub fn heuristic_base(size: u16) -> f32 {
+    2.0 * size as f32 * LINE_BASE
+}
+
+// Lower bound of board_heuristic on a board with tiles up to max_exponent.
+// Late-game boards go far below zero, so anything scored below every board,
+// such as a lost game, has to be scored below this.
+pub fn heuristic_floor(size: u16, max_exponent: u16) -> f32 {
+    let (size, exponent) = (size as f32, max_exponent as f32);
+    let sum = size * exponent.powf(SUM_POWER);
+    let monotonicity = (size - 1.0) * exponent.powf(MONOTONICITY_POWER);
+    2.0 * size * (LINE_BASE - SUM_WEIGHT * sum - MONOTONICITY_WEIGHT * monotonicity)
+}
+
+pub fn line_heuristic(line: &[u16]) -> f32 {
+    LineFeatures::new(line).heuristic()
+}
+
+// Computed line by line for boards without heuristic tables
+pub fn board_heuristic<B: Board>(state: &B) -> f32 {
+    let size = B::SIZE;
+    let mut line = vec![0; size as usize];
+    let mut heuristic = 0.0;
+    for i in 0..size {
+        for k in 0..size {
+            line[k as usize] = state.index(k, i);
+        }
+        heuristic += line_heuristic(&line);
+        for k in 0..size {
+            line[k as usize] = state.index(i, k);
+        }
+        heuristic += line_heuristic(&line);
+    }
+    heuristic
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::Grid;
+    use crate::game::State;
+
+    #[test]
+    fn test_line_components() {
+        assert_eq!(line_empty(&[0, 1, 0, 2]), 2);
+        assert_eq!(line_merges(&[1, 1, 2, 3]), 2);
+        assert_eq!(line_merges(&[1, 0, 1, 1]), 3);
+        assert_eq!(line_merges(&[1, 1, 2, 2]), 4);
+        assert_eq!(line_merges(&[1, 2, 1, 2]), 0);
+        assert_eq!(line_monotonicity(&[4, 3, 2, 1]), 0.0);
+        assert_eq!(line_monotonicity(&[1, 2, 3, 4]), 0.0);
+        assert_eq!(line_monotonicity(&[1, 2, 1, 0]), 15.0);
+        assert_eq!(line_sum(&[0, 1, 0, 1]), 2.0);
+    }
+
+    #[test]
+    fn test_board_heuristic() {
+        // Sorted tiles along an edge beat the same tiles scattered
+        let sorted = State::from_grid([[4, 3, 2, 1], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0]]);
+        let scattered = State::from_grid([[4, 0, 0, 0], [0, 0, 3, 0], [0, 1, 0, 0], [0, 0, 0, 2]]);
+        assert!(board_heuristic(&sorted) > board_heuristic(&scattered));
+
+        let grid = Grid::<3>::from_grid([[1, 1, 0], [0, 0, 0], [0, 0, 0]]);
+        let line = line_heuristic(&[1, 1, 0]) + line_heuristic(&[0, 0, 0]) * 3.0;
+        let expected = line + line_heuristic(&[1, 0, 0]) * 2.0;
+        assert_eq!(board_heuristic(&grid), expected);
+    }
+}
@@ -1,35 +1,54 @@
 /*
-Purely for benchmarking, code duplicated from main.rs
-Need to manually update this if main.rs changes
+The solver modules as a library, for the benchmarks and other crates.
+main.rs declares the same modules, so a new module goes in both.
 */
 
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+pub mod agent;
+use agent::{play_game, RandomAgent};
+
+pub mod board;
+use board::Board;
+
+pub mod book;
+
 pub mod game;
-use game::{Direction, State};
 
 pub mod precompute;
-use precompute::{get_possible_moves, load_precomputed as _load_precomputed, Precomputed};
+
+pub mod error;
+
+pub mod events;
+
+pub mod exact;
 
 pub mod expectimax;
-#[allow(unused_imports)]
-use expectimax::get_expectimax_move;
+
+pub mod heuristic;
+
+pub mod notation;
 
 pub mod random;
-#[allow(unused_imports)]
-use random::get_random_move;
 
-pub fn load_precomputed() -> Precomputed {
-    _load_precomputed()
-}
+pub mod rules;
+use rules::GameRules;
+
+pub mod session;
+use session::Game;
+
+pub mod storage;
+
+pub mod tablebase;
+
+pub mod transposition;
+
+pub mod zobrist;
 
-pub fn run_game(precomputed: &Precomputed) -> (u64, u64) {
-    let mut num_moves = 0;
-    let mut state = State::new();
-    let mut moves = get_possible_moves(state, precomputed);
-    while moves[0].0 != Direction::Invalid {
-        let (_move, new_state) = get_random_move(state, moves, precomputed);
-        state = new_state.add_random_tile();
-        num_moves += 1;
-        moves = get_possible_moves(state, precomputed);
-    }
-    (state.get_score(), num_moves)
+pub fn run_game<B: Board, R: Rng + ?Sized>(rules: &GameRules, rng: &mut R) -> (u64, u64) {
+    let agent = &mut RandomAgent::new(StdRng::seed_from_u64(rng.gen()));
+    // The random agent always has a legal move until the game ends
+    let game: Game<B> = play_game(agent, rules, rng, |_| {}).unwrap();
+    (game.score(), game.move_count() as u64)
 }
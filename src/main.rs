@@ -1,76 +1,247 @@
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::time::Duration;
+
+pub mod agent;
+use agent::{play_game, ExpectimaxAgent};
+
+pub mod board;
+use board::{Board, Grid};
+
+pub mod book;
+use book::{book_path, OpeningBook};
+
 pub mod game;
-use game::{Direction, State};
+use game::{State, WideState};
 
 pub mod precompute;
-use precompute::{
-    get_possible_moves, load_precomputed, precompute, Precomputed, TranspositionTable,
-};
+
+pub mod error;
+use error::SolverError;
+
+pub mod events;
+
+pub mod exact;
+use exact::{exact_table_path, ExactSolution};
 
 pub mod expectimax;
-#[allow(unused_imports)]
-use expectimax::get_expectimax_move;
+
+pub mod heuristic;
+
+pub mod notation;
 
 pub mod random;
-#[allow(unused_imports)]
-use random::get_random_move;
-
-#[allow(dead_code)]
-fn run_random_game(precomputed: &Precomputed) -> (u64, u64) {
-    let mut num_moves = 0;
-    let mut state = State::new();
-    let mut moves = get_possible_moves(state, precomputed);
-    while moves[0].0 != Direction::Invalid {
-        let (_move, new_state) = get_random_move(state, moves, precomputed);
-        state = new_state.add_random_tile();
-        num_moves += 1;
-        moves = get_possible_moves(state, precomputed);
+
+pub mod rules;
+use rules::GameRules;
+
+pub mod session;
+use session::Game;
+
+pub mod storage;
+
+pub mod tablebase;
+
+pub mod transposition;
+
+pub mod zobrist;
+
+// Deepest search when playing to a time budget
+const MAX_TIMED_DEPTH: u16 = 12;
+
+fn play<B: Board>(
+    depth: u16,
+    budget: Option<Duration>,
+    seed: u64,
+    rules: &GameRules,
+) -> Result<(), SolverError> {
+    let agent: &mut ExpectimaxAgent<B> = &mut ExpectimaxAgent::new(depth, rules);
+    if let Some(budget) = budget {
+        agent.set_time_budget(budget);
+    }
+    let path = book_path::<B>();
+    if path.exists() {
+        let book = OpeningBook::load(&path)?;
+        println!("Book:    \t{} positions", book.len());
+        agent.set_book(book)?;
+    }
+    let rng = &mut StdRng::seed_from_u64(seed);
+
+    let start = std::time::Instant::now();
+    let game: Game<B> = play_game(agent, rules, rng, |game| {
+        game.state().print_board();
+        println!("Score: {}", game.score());
+        println!();
+    })?;
+    let moves = game.move_count();
+    let time = start.elapsed().as_millis() as f32 / 1000.0;
+
+    println!("---");
+    println!("Seed:    \t{}", seed);
+    println!("Score:   \t{}", game.score());
+    println!(
+        "Target:  \t{} ({})",
+        2u64.pow(rules.target() as u32),
+        if rules.reached_target(&game.state()) {
+            "reached"
+        } else {
+            "missed"
+        }
+    );
+    // Tiles stop merging at the cap, so the game ends early
+    if game.state().max_exponent() == B::MAX_EXPONENT {
+        println!(
+            "Capped:  \t{} is the largest tile this board holds",
+            2u64.pow(B::MAX_EXPONENT as u32)
+        );
+    }
+    println!("Moves:   \t{}", moves);
+    println!("Time:    \t{}s", (time * 1000.0).round() / 1000.0);
+    println!("Moves/s: \t{}", (moves as f32 / time).round());
+    if budget.is_some() {
+        println!("Depth:   \t{:.2} on average", agent.mean_depth());
     }
-    (state.get_score(), num_moves)
+    let stats = agent.stats();
+    println!(
+        "Hit rate:\t{}% ({} lookups, peak {} entries)",
+        (stats.hit_rate() * 1000.0).round() / 10.0,
+        stats.lookups(),
+        stats.peak_size
+    );
+    Ok(())
+}
+
+fn parse_arg<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, SolverError> {
+    value
+        .parse::<T>()
+        .map_err(|_| SolverError::InvalidArgument {
+            name: name.to_string(),
+            value: value.to_string(),
+        })
 }
 
-#[allow(dead_code)]
-fn run_expectimax_game(
+// Adds games worth of deep results to the book for B, creating it if needed
+fn harvest<B: Board>(
     depth: u16,
-    precomputed: &Precomputed,
-    transposition: &mut TranspositionTable,
-) -> (u64, u64) {
-    let mut num_moves = 0;
-    let mut state = State::new();
-    let mut moves = get_possible_moves(state, precomputed);
-    while moves[0].0 != Direction::Invalid {
-        let (_move, new_state) =
-            get_expectimax_move(state, moves, depth, precomputed, transposition);
-        state = new_state.add_random_tile();
-        num_moves += 1;
-        moves = get_possible_moves(state, precomputed);
-        state.print_board()
+    games: usize,
+    plies: usize,
+    seed: u64,
+    rules: &GameRules,
+) -> Result<(), SolverError> {
+    let path = book_path::<B>();
+    let mut book: OpeningBook<B> = if path.exists() {
+        OpeningBook::load(&path)?
+    } else {
+        OpeningBook::new(rules)
+    };
+    let start = std::time::Instant::now();
+    book.harvest(games, depth, plies, &mut StdRng::seed_from_u64(seed))?;
+    book.save(&path)?;
+    println!("Book:    \t{} positions", book.len());
+    println!("Path:    \t{}", path.display());
+    println!("Time:    \t{}s", start.elapsed().as_secs());
+    Ok(())
+}
+
+fn run_book(args: &[String]) -> Result<(), SolverError> {
+    let depth = parse_arg::<u16>("depth", &args[0])?;
+    let games = parse_arg::<usize>("games", &args[1])?;
+    let plies = match args.get(2) {
+        Some(plies) => parse_arg::<usize>("plies", plies)?,
+        None => 200,
+    };
+    // 4w plays 4x4 on a wide board, which holds tiles past 32768
+    let size = args.get(3).map_or("4", String::as_str);
+    let seed = rand::random::<u64>();
+    println!("Seed:    \t{}", seed);
+
+    let rules = &GameRules::default();
+    match size {
+        "3" => harvest::<Grid<3>>(depth, games, plies, seed, rules),
+        "4" => harvest::<State>(depth, games, plies, seed, rules),
+        "4w" => harvest::<WideState>(depth, games, plies, seed, rules),
+        "5" => harvest::<Grid<5>>(depth, games, plies, seed, rules),
+        "6" => harvest::<Grid<6>>(depth, games, plies, seed, rules),
+        _ => Err(SolverError::InvalidArgument {
+            name: "size".to_string(),
+            value: size.to_string(),
+        }),
     }
-    (state.get_score(), num_moves)
 }
 
-fn main() {
-    if !std::path::Path::new("precomputed.json").exists() {
-        println!("Precomputing...");
-        precompute();
+// Solves the whole game on a small board and saves the optimal policy
+fn solve_exact<B: Board>(rules: &GameRules) -> Result<(), SolverError> {
+    let start = std::time::Instant::now();
+    let solution: ExactSolution<B> = ExactSolution::solve(rules);
+    print!("{}", solution.summary());
+    let path = exact_table_path::<B>();
+    solution.write_table(&path)?;
+    println!("Path:    \t{}", path.display());
+    println!("Time:    \t{}s", start.elapsed().as_secs());
+    Ok(())
+}
+
+fn run_exact(args: &[String]) -> Result<(), SolverError> {
+    let size = parse_arg::<u16>("size", &args[0])?;
+    let rules = &GameRules::default();
+    match size {
+        2 => solve_exact::<Grid<2>>(rules),
+        3 => solve_exact::<Grid<3>>(rules),
+        _ => Err(SolverError::InvalidArgument {
+            name: "size".to_string(),
+            value: size.to_string(),
+        }),
+    }
+}
+
+fn run(args: &[String]) -> Result<(), SolverError> {
+    if args[1] == "exact" && args.len() >= 3 {
+        return run_exact(&args[2..]);
     }
-    let precomputed: &Precomputed = &load_precomputed();
-    let transposition: &mut TranspositionTable = &mut TranspositionTable::new();
-    println!("Loaded precomputed data!");
+    if args[1] == "book" && args.len() >= 4 {
+        return run_book(&args[2..]);
+    }
+    // A fixed depth such as 3, or a time budget per move such as 50ms
+    let (depth, budget) = match args[1].strip_suffix("ms") {
+        Some(millis) => {
+            let millis = parse_arg::<u64>("budget", millis)?;
+            (MAX_TIMED_DEPTH, Some(Duration::from_millis(millis)))
+        }
+        None => (parse_arg::<u16>("depth", &args[1])?, None),
+    };
+    // Print the seed so any game can be replayed
+    let seed = match args.get(2) {
+        Some(seed) => parse_arg::<u64>("seed", seed)?,
+        None => rand::random::<u64>(),
+    };
+    // 4w plays 4x4 on a wide board, which holds tiles past 32768
+    let size = args.get(3).map_or("4", String::as_str);
+    println!("Seed:    \t{}", seed);
+
+    let rules = &GameRules::default();
+    match size {
+        "3" => play::<Grid<3>>(depth, budget, seed, rules),
+        "4" => play::<State>(depth, budget, seed, rules),
+        "4w" => play::<WideState>(depth, budget, seed, rules),
+        "5" => play::<Grid<5>>(depth, budget, seed, rules),
+        "6" => play::<Grid<6>>(depth, budget, seed, rules),
+        _ => Err(SolverError::InvalidArgument {
+            name: "size".to_string(),
+            value: size.to_string(),
+        }),
+    }
+}
 
+fn main() {
     let args: Vec<String> = std::env::args().collect();
     if args.len() < 2 {
-        println!("Usage: ./rust_solver <depth>");
+        println!("Usage: ./rust_solver <depth | budget, e.g. 50ms> [seed] [size, or 4w]");
+        println!("       ./rust_solver book <depth> <games> [plies] [size]");
+        println!("       ./rust_solver exact <size>");
         return;
     }
-    let depth = args[1].parse::<u16>().unwrap();
-
-    let start = std::time::Instant::now();
-    let (score, moves) = run_expectimax_game(depth, precomputed, transposition);
-    let time = start.elapsed().as_millis() as f32 / 1000.0;
-
-    println!("---");
-    println!("Score:   \t{}", score);
-    println!("Moves:   \t{}", moves);
-    println!("Time:    \t{}s", (time * 1000.0).round() / 1000.0);
-    println!("Moves/s: \t{}", (moves as f32 / time).round());
+    if let Err(err) = run(&args) {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    }
 }
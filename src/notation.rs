@@ -0,0 +1,351 @@
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+use super::board::{Board, Grid};
+use super::game::{State, WideState};
+
+/*
+BOARD NOTATION
+*/
+
+// Two notations are accepted, both listing rows from the top:
+//   tiles: "2 4 . ./. . 8 ./. . . ./. . . 2048", real values, . or 0 for empty
+//   hex:   "1200003000000b00", one exponent per cell, as printed by {:x}
+// Display writes tile notation.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseBoardError {
+    WrongRowCount {
+        expected: usize,
+        found: usize,
+    },
+    WrongRowLength {
+        row: usize,
+        expected: usize,
+        found: usize,
+    },
+    WrongHexLength {
+        expected: usize,
+        found: usize,
+    },
+    InvalidTile(String),
+    TileTooLarge {
+        tile: String,
+        max: u64,
+    },
+}
+
+impl fmt::Display for ParseBoardError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseBoardError::WrongRowCount { expected, found } => {
+                write!(f, "expected {} rows, found {}", expected, found)
+            }
+            ParseBoardError::WrongRowLength {
+                row,
+                expected,
+                found,
+            } => write!(
+                f,
+                "expected {} tiles in row {}, found {}",
+                expected, row, found
+            ),
+            ParseBoardError::WrongHexLength { expected, found } => {
+                write!(f, "expected {} hex digits, found {}", expected, found)
+            }
+            ParseBoardError::InvalidTile(tile) => {
+                write!(
+                    f,
+                    "'{}' is not a tile, tiles are powers of two from 2",
+                    tile
+                )
+            }
+            ParseBoardError::TileTooLarge { tile, max } => {
+                write!(
+                    f,
+                    "tile {} does not fit on this board, max is {}",
+                    tile, max
+                )
+            }
+        }
+    }
+}
+
+impl Error for ParseBoardError {}
+
+fn parse_tile(tile: &str, max_exponent: u16) -> Result<u16, ParseBoardError> {
+    if tile == "." {
+        return Ok(0);
+    }
+    let value = match tile.parse::<u128>() {
+        Ok(value) => value,
+        Err(_) => return Err(ParseBoardError::InvalidTile(tile.to_string())),
+    };
+    if value == 0 {
+        return Ok(0);
+    }
+    if value == 1 || !value.is_power_of_two() {
+        return Err(ParseBoardError::InvalidTile(tile.to_string()));
+    }
+    let exponent = value.trailing_zeros() as u16;
+    if exponent > max_exponent {
+        return Err(ParseBoardError::TileTooLarge {
+            tile: tile.to_string(),
+            max: 1 << max_exponent.min(63),
+        });
+    }
+    Ok(exponent)
+}
+
+fn parse_hex_digit(digit: char, max_exponent: u16) -> Result<u16, ParseBoardError> {
+    match digit.to_digit(16) {
+        Some(exponent) if exponent as u16 <= max_exponent => Ok(exponent as u16),
+        Some(exponent) => Err(ParseBoardError::TileTooLarge {
+            tile: (1u64 << exponent).to_string(),
+            max: 1 << max_exponent.min(63),
+        }),
+        None => Err(ParseBoardError::InvalidTile(digit.to_string())),
+    }
+}
+
+pub fn parse_board<B: Board>(text: &str) -> Result<B, ParseBoardError> {
+    let size = B::SIZE as usize;
+    let text = text.trim();
+    let mut state = B::empty();
+
+    if !text.contains('/') {
+        let digits: Vec<char> = text.trim_start_matches("0x").chars().collect();
+        if digits.len() != size * size {
+            return Err(ParseBoardError::WrongHexLength {
+                expected: size * size,
+                found: digits.len(),
+            });
+        }
+        for (i, digit) in digits.into_iter().enumerate() {
+            let exponent = parse_hex_digit(digit, B::MAX_EXPONENT)?;
+            state.set_tile((i % size) as u16, (i / size) as u16, exponent);
+        }
+        return Ok(state);
+    }
+
+    let rows: Vec<&str> = text.split('/').collect();
+    if rows.len() != size {
+        return Err(ParseBoardError::WrongRowCount {
+            expected: size,
+            found: rows.len(),
+        });
+    }
+    for (y, row) in rows.iter().enumerate() {
+        let tiles: Vec<&str> = row.split_whitespace().collect();
+        if tiles.len() != size {
+            return Err(ParseBoardError::WrongRowLength {
+                row: y,
+                expected: size,
+                found: tiles.len(),
+            });
+        }
+        for (x, tile) in tiles.iter().enumerate() {
+            let exponent = parse_tile(tile, B::MAX_EXPONENT)?;
+            state.set_tile(x as u16, y as u16, exponent);
+        }
+    }
+    Ok(state)
+}
+
+pub fn format_board<B: Board>(state: &B, f: &mut fmt::Formatter) -> fmt::Result {
+    for y in 0..B::SIZE {
+        if y > 0 {
+            write!(f, "/")?;
+        }
+        for x in 0..B::SIZE {
+            if x > 0 {
+                write!(f, " ")?;
+            }
+            match state.index(x, y) {
+                0 => write!(f, ".")?,
+                exponent => write!(f, "{}", 1u128 << exponent)?,
+            }
+        }
+    }
+    Ok(())
+}
+
+// None if a tile is too large for a single hex digit
+pub fn to_hex<B: Board>(state: &B) -> Option<String> {
+    let mut hex = String::new();
+    for y in 0..B::SIZE {
+        for x in 0..B::SIZE {
+            hex.push(std::char::from_digit(state.index(x, y) as u32, 16)?);
+        }
+    }
+    Some(hex)
+}
+
+impl FromStr for State {
+    type Err = ParseBoardError;
+
+    fn from_str(text: &str) -> Result<State, ParseBoardError> {
+        parse_board(text)
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        format_board(self, f)
+    }
+}
+
+impl fmt::LowerHex for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}", self.board)
+    }
+}
+
+impl FromStr for WideState {
+    type Err = ParseBoardError;
+
+    fn from_str(text: &str) -> Result<WideState, ParseBoardError> {
+        parse_board(text)
+    }
+}
+
+impl fmt::Display for WideState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        format_board(self, f)
+    }
+}
+
+impl<const N: usize> FromStr for Grid<N> {
+    type Err = ParseBoardError;
+
+    fn from_str(text: &str) -> Result<Grid<N>, ParseBoardError> {
+        parse_board(text)
+    }
+}
+
+impl<const N: usize> fmt::Display for Grid<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        format_board(self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tiles() {
+        let state: State = "2 4 . ./. . 8 ./0 . . ./. . . 2048".parse().unwrap();
+        assert_eq!(
+            state.to_grid(),
+            [[1, 2, 0, 0], [0, 0, 3, 0], [0, 0, 0, 0], [0, 0, 0, 11]]
+        );
+        assert_eq!(state.to_string(), "2 4 . ./. . 8 ./. . . ./. . . 2048");
+
+        let state: State = " 2  4 . . / . . 8 . / . . . . / . . . 32768 "
+            .parse()
+            .unwrap();
+        assert_eq!(state.index(3, 3), 15);
+    }
+
+    #[test]
+    fn test_parse_hex() {
+        let state: State = "1200003000000b00".parse().unwrap();
+        assert_eq!(state.to_string(), "2 4 . ./. . 8 ./. . . ./. 2048 . .");
+        assert_eq!(format!("{:x}", state), "1200003000000b00");
+        assert_eq!(to_hex(&state), Some("1200003000000b00".to_string()));
+        assert_eq!("0x1200003000000B00".parse::<State>(), Ok(state));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let state =
+            State::from_grid([[0, 15, 14, 13], [12, 11, 10, 9], [8, 7, 6, 5], [4, 3, 2, 1]]);
+        assert_eq!(state.to_string().parse::<State>(), Ok(state));
+        assert_eq!(format!("{:x}", state).parse::<State>(), Ok(state));
+
+        let wide = WideState::from_grid([[17, 16, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 1]]);
+        assert_eq!(wide.to_string(), "131072 65536 . ./. . . ./. . . ./. . . 2");
+        assert_eq!(wide.to_string().parse::<WideState>(), Ok(wide));
+        assert_eq!(to_hex(&wide), None);
+        // Too large for State, not for WideState
+        assert_eq!(
+            "65536 . . ./. . . ./. . . ./. . . .".parse::<WideState>(),
+            Ok(WideState::from_grid([
+                [16, 0, 0, 0],
+                [0, 0, 0, 0],
+                [0, 0, 0, 0],
+                [0, 0, 0, 0]
+            ]))
+        );
+
+        let grid = Grid::<3>::from_grid([[1, 0, 0], [0, 2, 0], [0, 0, 3]]);
+        assert_eq!(grid.to_string(), "2 . ./. 4 ./. . 8");
+        assert_eq!("100020003".parse::<Grid<3>>(), Ok(grid));
+        assert_eq!(
+            "1000000f0".parse::<Grid<3>>(),
+            Ok(Grid::from_grid([[1, 0, 0], [0, 0, 0], [0, 15, 0]]))
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            "2 4 . ./. . 8 .".parse::<State>(),
+            Err(ParseBoardError::WrongRowCount {
+                expected: 4,
+                found: 2
+            })
+        );
+        assert_eq!(
+            "2 4 ./. . 8 ./. . . ./. . . .".parse::<State>(),
+            Err(ParseBoardError::WrongRowLength {
+                row: 0,
+                expected: 4,
+                found: 3
+            })
+        );
+        assert_eq!(
+            "2 3 . ./. . . ./. . . ./. . . .".parse::<State>(),
+            Err(ParseBoardError::InvalidTile("3".to_string()))
+        );
+        assert_eq!(
+            "1 . . ./. . . ./. . . ./. . . .".parse::<State>(),
+            Err(ParseBoardError::InvalidTile("1".to_string()))
+        );
+        assert_eq!(
+            "x . . ./. . . ./. . . ./. . . .".parse::<State>(),
+            Err(ParseBoardError::InvalidTile("x".to_string()))
+        );
+        assert_eq!(
+            "65536 . . ./. . . ./. . . ./. . . .".parse::<State>(),
+            Err(ParseBoardError::TileTooLarge {
+                tile: "65536".to_string(),
+                max: 32768
+            })
+        );
+        assert_eq!(
+            "12".parse::<State>(),
+            Err(ParseBoardError::WrongHexLength {
+                expected: 16,
+                found: 2
+            })
+        );
+        assert_eq!(
+            "12000030000000g0".parse::<State>(),
+            Err(ParseBoardError::InvalidTile("g".to_string()))
+        );
+        assert_eq!(
+            "10000000".parse::<Grid<3>>(),
+            Err(ParseBoardError::WrongHexLength {
+                expected: 9,
+                found: 8
+            })
+        );
+        assert_eq!(
+            "1000000g0".parse::<Grid<3>>(),
+            Err(ParseBoardError::InvalidTile("g".to_string()))
+        );
+    }
+}
@@ -1,315 +1,1061 @@
 use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
 use serde::{Deserialize, Serialize};
-use serde_json::{from_str, to_string};
 
-#[allow(unused_imports)]
-use crate::game::{move_left, move_right, Direction, State};
+use crate::board::Board;
+use crate::error::SolverError;
+use crate::events::MoveResult;
+use crate::game::{
+    merge_score, move_left, move_right, trace_left, trace_right, Direction, State, WideState,
+    MAX_EXPONENT, MAX_WIDE_EXPONENT,
+};
+use crate::heuristic::LineFeatures;
+use crate::storage::{data_path, write_atomic};
+use crate::transposition::{transposition_key, Transposition, TranspositionStats};
+use crate::zobrist::ZobristBuildHasher;
 
 /*
 PRECOMPUTATION INFRASTRUCTURE
 */
 
-#[derive(Serialize, Deserialize)]
 pub struct Precomputed {
-    move_left: Vec<[u16; 4]>,
-    move_right: Vec<[u16; 4]>,
+    move_left: Vec<u16>,
+    move_right: Vec<u16>,
+    // Points earned by sliding the row, the same in both directions
+    merge_score: Vec<u32>,
+    // Destination of each cell packed like the row, see game::trace_left
+    trace_left: Vec<u16>,
+    trace_right: Vec<u16>,
+    // Heuristic components of the row, see heuristic::LineFeatures
+    row_sum: Vec<f32>,
+    row_monotonicity: Vec<f32>,
+    row_empty: Vec<u8>,
+    row_merges: Vec<u8>,
+    // The components combined, so a leaf costs 8 lookups
+    row_heuristic: Vec<f32>,
+    // move_left and move_right indexed by a column, see State::col, with the
+    // result spread down column 0 so vertical moves need no transpose
+    col_up: Vec<u64>,
+    col_down: Vec<u64>,
+}
+
+/*
+TABLE FILE FORMAT
+*/
+
+// Little endian throughout:
+//   magic (8 bytes) | version (u32) | rules fingerprint (u64)
+//   move_left, move_right (u16 each) | merge_score (u32) | trace_left, trace_right (u16)
+//   row_sum, row_monotonicity (f32) | row_empty, row_merges (u8)
+//   checksum of everything before it (u64)
+// row_heuristic is rebuilt from the components, so the weights can change
+// without a new version, and the column tables from the row moves.
+const TABLE_MAGIC: &[u8; 8] = b"2048TBL\0";
+const TABLE_VERSION: u32 = 2;
+const TABLE_FILE: &str = "precomputed.bin";
+const ROW_COUNT: usize = 1 << 16;
+const HEADER_LEN: usize = 8 + 4 + 8;
+const TABLE_FILE_LEN: usize = HEADER_LEN + ROW_COUNT * (2 * 4 + 4 + 4 * 2 + 2) + 8;
+
+// FNV-1a
+fn _checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
 }
 
-fn _save_precomputed(file_name: String, data: Precomputed) {
-    let json_string = to_string(&data).unwrap();
-    std::fs::write(file_name, json_string).unwrap();
+// Changes whenever the move rules behind the tables change, so a reader can
+// tell a file written by an older solver apart instead of using wrong moves
+fn _rules_fingerprint() -> u64 {
+    let probes = [
+        [1, 1, 1, 1],
+        [1, 2, 2, 1],
+        [0, 3, 0, 3],
+        [2, 2, 4, 8],
+        [MAX_EXPONENT, MAX_EXPONENT, 1, 1],
+    ];
+    let mut bytes = vec![MAX_EXPONENT as u8, 4, 4];
+    for probe in probes {
+        let moved = move_left(probe, MAX_EXPONENT);
+        bytes.extend(moved.iter().map(|tile| *tile as u8));
+        bytes.extend(merge_score(probe, MAX_EXPONENT).to_le_bytes());
+        bytes.extend(
+            trace_left(probe, MAX_EXPONENT)
+                .iter()
+                .map(|cell| *cell as u8),
+        );
+    }
+    _checksum(&bytes)
 }
 
-fn _load_precomputed(file_name: String) -> Precomputed {
-    let json_string = std::fs::read_to_string(file_name).unwrap();
-    from_str(&json_string).unwrap()
+fn _encode_precomputed(data: &Precomputed) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(TABLE_FILE_LEN);
+    bytes.extend(TABLE_MAGIC);
+    bytes.extend(TABLE_VERSION.to_le_bytes());
+    bytes.extend(_rules_fingerprint().to_le_bytes());
+    for table in [&data.move_left, &data.move_right] {
+        bytes.extend(table.iter().flat_map(|row| row.to_le_bytes()));
+    }
+    bytes.extend(
+        data.merge_score
+            .iter()
+            .flat_map(|score| score.to_le_bytes()),
+    );
+    for table in [&data.trace_left, &data.trace_right] {
+        bytes.extend(table.iter().flat_map(|row| row.to_le_bytes()));
+    }
+    for table in [&data.row_sum, &data.row_monotonicity] {
+        bytes.extend(table.iter().flat_map(|value| value.to_le_bytes()));
+    }
+    bytes.extend(&data.row_empty);
+    bytes.extend(&data.row_merges);
+    bytes.extend(_checksum(&bytes).to_le_bytes());
+    bytes
 }
 
-fn _precompute_move(func: fn([u16; 4]) -> [u16; 4]) -> Vec<[u16; 4]> {
-    let mut precomputed = vec![[0; 4]; 65536];
-    for i in 0..16 {
-        for j in 0..16 {
-            for k in 0..16 {
-                for l in 0..16 {
-                    let result = func([i, j, k, l]);
-                    let index = (i << 12) + (j << 8) + (k << 4) + l;
-                    precomputed[index as usize] = result;
-                }
+// Reads an exported file back. The solver always builds its own tables, so
+// only the tests read the format, to check what other tools will see.
+#[cfg(test)]
+fn _decode_precomputed(bytes: &[u8]) -> Result<Precomputed, String> {
+    let invalid = |reason: &str| Err(reason.to_string());
+    if bytes.len() < HEADER_LEN || &bytes[..8] != TABLE_MAGIC {
+        return invalid("not a move table file");
+    }
+    let version = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+    if version != TABLE_VERSION {
+        return invalid(&format!(
+            "format version {}, expected {}",
+            version, TABLE_VERSION
+        ));
+    }
+    if u64::from_le_bytes(bytes[12..20].try_into().unwrap()) != _rules_fingerprint() {
+        return invalid("built for different move rules");
+    }
+    if bytes.len() != TABLE_FILE_LEN {
+        return invalid("truncated");
+    }
+    let (body, checksum) = bytes.split_at(TABLE_FILE_LEN - 8);
+    if u64::from_le_bytes(checksum.try_into().unwrap()) != _checksum(body) {
+        return invalid("checksum mismatch");
+    }
+
+    let mut tables = &body[HEADER_LEN..];
+    let mut take = |width: usize| {
+        let (table, rest) = tables.split_at(width * ROW_COUNT);
+        tables = rest;
+        table
+    };
+    let move_left = _read_u16s(take(2));
+    let move_right = _read_u16s(take(2));
+    let merge_score = _read_u32s(take(4));
+    let trace_left = _read_u16s(take(2));
+    let trace_right = _read_u16s(take(2));
+    let row_sum = _read_f32s(take(4));
+    let row_monotonicity = _read_f32s(take(4));
+    let row_empty = take(1).to_vec();
+    let row_merges = take(1).to_vec();
+    let row_heuristic = (0..ROW_COUNT)
+        .map(|row| {
+            LineFeatures {
+                sum: row_sum[row],
+                monotonicity: row_monotonicity[row],
+                empty: row_empty[row],
+                merges: row_merges[row],
             }
-        }
+            .heuristic()
+        })
+        .collect();
+    let col_up = _spread_columns(&move_left);
+    let col_down = _spread_columns(&move_right);
+    Ok(Precomputed {
+        move_left,
+        move_right,
+        merge_score,
+        trace_left,
+        trace_right,
+        row_sum,
+        row_monotonicity,
+        row_empty,
+        row_merges,
+        row_heuristic,
+        col_up,
+        col_down,
+    })
+}
+
+#[cfg(test)]
+fn _read_u16s(bytes: &[u8]) -> Vec<u16> {
+    bytes
+        .chunks_exact(2)
+        .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+        .collect()
+}
+
+#[cfg(test)]
+fn _read_u32s(bytes: &[u8]) -> Vec<u32> {
+    bytes
+        .chunks_exact(4)
+        .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .collect()
+}
+
+#[cfg(test)]
+fn _read_f32s(bytes: &[u8]) -> Vec<f32> {
+    _read_u32s(bytes).into_iter().map(f32::from_bits).collect()
+}
+
+fn _save_precomputed(path: &Path, data: &Precomputed) -> Result<(), SolverError> {
+    write_atomic(path, &_encode_precomputed(data))
+}
+
+// Tables are indexed by the row packed with `bits` bits per cell
+fn _unpack_row(index: u32, bits: u32) -> [u16; 4] {
+    let mask = (1 << bits) - 1;
+    [
+        ((index >> (3 * bits)) & mask) as u16,
+        ((index >> (2 * bits)) & mask) as u16,
+        ((index >> bits) & mask) as u16,
+        (index & mask) as u16,
+    ]
+}
+
+fn _precompute_move(func: fn([u16; 4], u16) -> [u16; 4], bits: u32, max_exponent: u16) -> Vec<u32> {
+    let mut precomputed = vec![0; 1 << (4 * bits)];
+    for (index, value) in precomputed.iter_mut().enumerate() {
+        let result = func(_unpack_row(index as u32, bits), max_exponent);
+        *value = result
+            .iter()
+            .fold(0, |acc, tile| (acc << bits) | *tile as u32);
+    }
+
+    precomputed
+}
+
+fn _precompute_score(bits: u32, max_exponent: u16) -> Vec<u64> {
+    let mut precomputed = vec![0; 1 << (4 * bits)];
+    for (index, value) in precomputed.iter_mut().enumerate() {
+        *value = merge_score(_unpack_row(index as u32, bits), max_exponent);
     }
 
     precomputed
 }
 
-pub fn precompute() {
-    // TODO: document what each of these are
-    let move_left = _precompute_move(move_left);
-    let move_right = _precompute_move(move_right);
-    let precomputed = Precomputed {
+fn _precompute_narrow_move(func: fn([u16; 4], u16) -> [u16; 4]) -> Vec<u16> {
+    _precompute_move(func, 4, MAX_EXPONENT)
+        .into_iter()
+        .map(|row| row as u16)
+        .collect()
+}
+
+fn _precompute_features() -> Vec<LineFeatures> {
+    (0..1 << 16)
+        .map(|index| LineFeatures::new(&_unpack_row(index, 4)))
+        .collect()
+}
+
+// Places each moved row down column 0 of a board, top cell first
+fn _spread_columns(moves: &[u16]) -> Vec<u64> {
+    moves
+        .iter()
+        .map(|&row| {
+            let row = row as u64;
+            ((row & 0xF000) << 48)
+                | ((row & 0x0F00) << 36)
+                | ((row & 0x00F0) << 24)
+                | ((row & 0x000F) << 12)
+        })
+        .collect()
+}
+
+// Row lookups indexed by the packed 16-bit row, see State::row
+pub fn compute_precomputed() -> Precomputed {
+    let features = _precompute_features();
+    let move_left = _precompute_narrow_move(move_left);
+    let move_right = _precompute_narrow_move(move_right);
+    Precomputed {
+        col_up: _spread_columns(&move_left),
+        col_down: _spread_columns(&move_right),
         move_left,
         move_right,
-    };
-    _save_precomputed("precomputed.json".to_string(), precomputed);
+        merge_score: _precompute_score(4, MAX_EXPONENT)
+            .into_iter()
+            .map(|score| score as u32)
+            .collect(),
+        trace_left: _precompute_narrow_move(trace_left),
+        trace_right: _precompute_narrow_move(trace_right),
+        row_sum: features.iter().map(|row| row.sum).collect(),
+        row_monotonicity: features.iter().map(|row| row.monotonicity).collect(),
+        row_empty: features.iter().map(|row| row.empty).collect(),
+        row_merges: features.iter().map(|row| row.merges).collect(),
+        row_heuristic: features.iter().map(|row| row.heuristic()).collect(),
+    }
+}
+
+// Built on first use and shared by the whole process
+static TABLES: OnceLock<Precomputed> = OnceLock::new();
+
+pub fn tables() -> &'static Precomputed {
+    TABLES.get_or_init(compute_precomputed)
+}
+
+// Default location of the table file, see storage::data_dir
+pub fn table_path() -> PathBuf {
+    data_path(TABLE_FILE)
+}
+
+pub fn precompute() -> Result<PathBuf, SolverError> {
+    let path = table_path();
+    precompute_to(&path)?;
+    Ok(path)
 }
 
-pub fn load_precomputed() -> Precomputed {
-    _load_precomputed("precomputed.json".to_string())
+// The solver never reads this file, tables() always builds the tables in
+// memory. It is an export of the tables for other tools, see TABLE FILE FORMAT.
+pub fn precompute_to(path: &Path) -> Result<(), SolverError> {
+    _save_precomputed(path, &compute_precomputed())
 }
 
 /*
 PRECOMPUTED FUNCTIONS
 */
 
-pub fn move_state(
-    rows: (u16, u16, u16, u16),
-    cols: (u16, u16, u16, u16),
-    direction: Direction,
-    precomputed: &Precomputed,
-) -> State {
+fn move_rows(state: State, table: &[u16]) -> State {
+    let rows = state.rows();
+    State::from_rows([
+        table[rows[0] as usize],
+        table[rows[1] as usize],
+        table[rows[2] as usize],
+        table[rows[3] as usize],
+    ])
+}
+
+fn move_cols(state: State, table: &[u64]) -> State {
+    let cols = state.cols();
+    State {
+        board: table[cols[0] as usize]
+            | (table[cols[1] as usize] >> 4)
+            | (table[cols[2] as usize] >> 8)
+            | (table[cols[3] as usize] >> 12),
+    }
+}
+
+pub fn move_state(state: State, direction: Direction) -> State {
+    let precomputed = tables();
     match direction {
-        Direction::Left | Direction::Right => match direction {
-            Direction::Left => State {
-                grid: [
-                    precomputed.move_left[rows.0 as usize],
-                    precomputed.move_left[rows.1 as usize],
-                    precomputed.move_left[rows.2 as usize],
-                    precomputed.move_left[rows.3 as usize],
-                ],
-            },
-            Direction::Right => State {
-                grid: [
-                    precomputed.move_right[rows.0 as usize],
-                    precomputed.move_right[rows.1 as usize],
-                    precomputed.move_right[rows.2 as usize],
-                    precomputed.move_right[rows.3 as usize],
-                ],
-            },
-            _ => unreachable!(),
-        },
-        Direction::Up | Direction::Down => match direction {
-            Direction::Up => State {
-                grid: [
-                    precomputed.move_left[cols.0 as usize],
-                    precomputed.move_left[cols.1 as usize],
-                    precomputed.move_left[cols.2 as usize],
-                    precomputed.move_left[cols.3 as usize],
-                ],
-            }
-            .transpose(),
-            Direction::Down => State {
-                grid: [
-                    precomputed.move_right[cols.0 as usize],
-                    precomputed.move_right[cols.1 as usize],
-                    precomputed.move_right[cols.2 as usize],
-                    precomputed.move_right[cols.3 as usize],
-                ],
-            }
-            .transpose(),
-            _ => unreachable!(),
-        },
-        _ => unreachable!(),
+        Direction::Left => move_rows(state, &precomputed.move_left),
+        Direction::Right => move_rows(state, &precomputed.move_right),
+        Direction::Up => move_cols(state, &precomputed.col_up),
+        Direction::Down => move_cols(state, &precomputed.col_down),
+        Direction::Invalid => unreachable!(),
+    }
+}
+
+pub fn get_move_score(state: State, direction: Direction) -> u64 {
+    let precomputed = tables();
+    let rows = match direction {
+        Direction::Left | Direction::Right => state.rows(),
+        Direction::Up | Direction::Down => state.cols(),
+        Direction::Invalid => return 0,
+    };
+    rows.iter()
+        .map(|row| precomputed.merge_score[*row as usize] as u64)
+        .sum()
+}
+
+// Rows are lines along x for Left/Right and along y (transposed) for Up/Down
+fn _add_row_events<B>(
+    result: &mut MoveResult<B>,
+    direction: Direction,
+    rows: [u32; 4],
+    traces: [u32; 4],
+    moved_rows: [u32; 4],
+    bits: u32,
+) {
+    for (i, row) in rows.iter().enumerate() {
+        let cell = |k: usize| match direction {
+            Direction::Left | Direction::Right => (k as u16, i as u16),
+            _ => (i as u16, k as u16),
+        };
+        result.add_line(
+            cell,
+            &_unpack_row(*row, bits),
+            &_unpack_row(traces[i], bits),
+            &_unpack_row(moved_rows[i], bits),
+        );
+    }
+}
+
+pub fn move_state_with_events(state: State, direction: Direction) -> MoveResult<State> {
+    let precomputed = tables();
+    if direction == Direction::Invalid {
+        return MoveResult::new(direction, state);
+    }
+    let next_state = move_state(state, direction);
+    let mut result = MoveResult::new(direction, next_state);
+    let (rows, moved_rows, table) = match direction {
+        Direction::Left => (state.rows(), next_state.rows(), &precomputed.trace_left),
+        Direction::Right => (state.rows(), next_state.rows(), &precomputed.trace_right),
+        Direction::Up => (state.cols(), next_state.cols(), &precomputed.trace_left),
+        Direction::Down => (state.cols(), next_state.cols(), &precomputed.trace_right),
+        Direction::Invalid => unreachable!(),
+    };
+    _add_row_events(
+        &mut result,
+        direction,
+        rows.map(|row| row as u32),
+        rows.map(|row| table[row as usize] as u32),
+        moved_rows.map(|row| row as u32),
+        4,
+    );
+    result
+}
+
+// Same as heuristic::board_heuristic, from the rows and the transposed rows
+pub fn get_heuristic(state: State) -> f32 {
+    let precomputed = tables();
+    let rows = state.rows();
+    let cols = state.transpose().rows();
+    rows.iter()
+        .chain(cols.iter())
+        .map(|row| precomputed.row_heuristic[*row as usize])
+        .sum()
+}
+
+pub fn get_row_features(row: u16) -> LineFeatures {
+    let precomputed = tables();
+    LineFeatures {
+        sum: precomputed.row_sum[row as usize],
+        monotonicity: precomputed.row_monotonicity[row as usize],
+        empty: precomputed.row_empty[row as usize],
+        merges: precomputed.row_merges[row as usize],
     }
 }
 
-pub fn get_possible_moves(state: State, precomputed: &Precomputed) -> [(Direction, State); 4] {
-    let rows = state.row_ints();
-    let cols = state.col_ints();
+pub fn get_possible_moves(state: State) -> [(Direction, State); 4] {
+    let precomputed = tables();
 
     let mut moves = [(Direction::Invalid, state); 4];
     let mut index = 0;
-    let left = move_state(rows, cols, Direction::Left, precomputed);
+    let left = move_rows(state, &precomputed.move_left);
     if left != state {
         moves[index] = (Direction::Left, left);
         index += 1;
     }
-    let right = move_state(rows, cols, Direction::Right, precomputed);
+    let right = move_rows(state, &precomputed.move_right);
     if right != state {
         moves[index] = (Direction::Right, right);
         index += 1;
     }
-    let up = move_state(rows, cols, Direction::Up, precomputed);
+    let up = move_cols(state, &precomputed.col_up);
     if up != state {
         moves[index] = (Direction::Up, up);
         index += 1;
     }
-    let down = move_state(rows, cols, Direction::Down, precomputed);
+    let down = move_cols(state, &precomputed.col_down);
     if down != state {
         moves[index] = (Direction::Down, down);
     }
     moves
 }
 
-pub fn is_game_over(state: State, precomputed: &Precomputed) -> bool {
-    let rows = state.row_ints();
-    // hack since Direction::Left doesn't need cols
-    if move_state(rows, rows, Direction::Left, precomputed) != state {
-        return false;
+// A full board is over unless two neighbours are equal, which shows up as a
+// zero nibble when the board is XORed with itself shifted by one cell
+pub fn is_game_over(state: State) -> bool {
+    // Low bit of each nibble is set if the nibble is non-zero
+    let non_zero = |x: u64| {
+        let x = x | ((x >> 2) & 0x3333_3333_3333_3333);
+        x | (x >> 1)
+    };
+    // Low bit of each nibble is set if the nibble is 0xF
+    let full = |x: u64| x & (x >> 1) & (x >> 2) & (x >> 3);
+    let board = state.board;
+    // Empty cells, then each cell against its left neighbour, then against the
+    // cell above. The first cell of a row or column has no neighbour to check.
+    // Two 0xF tiles cannot merge, the result would not fit in a nibble.
+    let empty = !non_zero(board) & 0x1111_1111_1111_1111;
+    let across = !non_zero(board ^ (board >> 4)) & !full(board & (board >> 4));
+    let down = !non_zero(board ^ (board >> 16)) & !full(board & (board >> 16));
+    empty | (across & 0x0111_0111_0111_0111) | (down & 0x0000_1111_1111_1111) == 0
+}
+
+/*
+WIDE PRECOMPUTED FUNCTIONS
+*/
+
+// Row lookups indexed by the packed 20-bit row, see WideState::row. These are
+// 16x larger than Precomputed, so they are never saved.
+pub struct WidePrecomputed {
+    move_left: Vec<u32>,
+    move_right: Vec<u32>,
+    merge_score: Vec<u64>,
+    trace_left: Vec<u32>,
+    trace_right: Vec<u32>,
+}
+
+pub fn compute_wide_precomputed() -> WidePrecomputed {
+    WidePrecomputed {
+        move_left: _precompute_move(move_left, 5, MAX_WIDE_EXPONENT),
+        move_right: _precompute_move(move_right, 5, MAX_WIDE_EXPONENT),
+        merge_score: _precompute_score(5, MAX_WIDE_EXPONENT),
+        trace_left: _precompute_move(trace_left, 5, MAX_WIDE_EXPONENT),
+        trace_right: _precompute_move(trace_right, 5, MAX_WIDE_EXPONENT),
     }
-    let cols = state.col_ints();
-    if move_state(rows, cols, Direction::Right, precomputed) != state {
-        return false;
+}
+
+static WIDE_TABLES: OnceLock<WidePrecomputed> = OnceLock::new();
+
+pub fn wide_tables() -> &'static WidePrecomputed {
+    WIDE_TABLES.get_or_init(compute_wide_precomputed)
+}
+
+fn move_wide_rows(state: WideState, table: &[u32]) -> WideState {
+    let rows = state.rows();
+    WideState::from_rows([
+        table[rows[0] as usize],
+        table[rows[1] as usize],
+        table[rows[2] as usize],
+        table[rows[3] as usize],
+    ])
+}
+
+pub fn move_wide_state(state: WideState, direction: Direction) -> WideState {
+    let precomputed = wide_tables();
+    match direction {
+        Direction::Left => move_wide_rows(state, &precomputed.move_left),
+        Direction::Right => move_wide_rows(state, &precomputed.move_right),
+        Direction::Up => move_wide_rows(state.transpose(), &precomputed.move_left).transpose(),
+        Direction::Down => move_wide_rows(state.transpose(), &precomputed.move_right).transpose(),
+        Direction::Invalid => unreachable!(),
     }
-    if move_state(rows, cols, Direction::Up, precomputed) != state {
-        return false;
+}
+
+pub fn get_wide_move_score(state: WideState, direction: Direction) -> u64 {
+    let precomputed = wide_tables();
+    let rows = match direction {
+        Direction::Left | Direction::Right => state.rows(),
+        Direction::Up | Direction::Down => state.transpose().rows(),
+        Direction::Invalid => return 0,
+    };
+    rows.iter()
+        .map(|row| precomputed.merge_score[*row as usize])
+        .sum()
+}
+
+pub fn move_wide_state_with_events(
+    state: WideState,
+    direction: Direction,
+) -> MoveResult<WideState> {
+    let precomputed = wide_tables();
+    if direction == Direction::Invalid {
+        return MoveResult::new(direction, state);
     }
-    if move_state(rows, cols, Direction::Down, precomputed) != state {
-        return false;
+    let next_state = move_wide_state(state, direction);
+    let mut result = MoveResult::new(direction, next_state);
+    let (rows, moved_rows, table) = match direction {
+        Direction::Left => (state.rows(), next_state.rows(), &precomputed.trace_left),
+        Direction::Right => (state.rows(), next_state.rows(), &precomputed.trace_right),
+        Direction::Up => (
+            state.transpose().rows(),
+            next_state.transpose().rows(),
+            &precomputed.trace_left,
+        ),
+        Direction::Down => (
+            state.transpose().rows(),
+            next_state.transpose().rows(),
+            &precomputed.trace_right,
+        ),
+        Direction::Invalid => unreachable!(),
+    };
+    let traces = rows.map(|row| table[row as usize]);
+    _add_row_events(&mut result, direction, rows, traces, moved_rows, 5);
+    result
+}
+
+pub fn get_possible_wide_moves(state: WideState) -> [(Direction, WideState); 4] {
+    let mut moves = [(Direction::Invalid, state); 4];
+    let mut index = 0;
+    for direction in [
+        Direction::Left,
+        Direction::Right,
+        Direction::Up,
+        Direction::Down,
+    ] {
+        let next_state = move_wide_state(state, direction);
+        if next_state != state {
+            moves[index] = (direction, next_state);
+            index += 1;
+        }
     }
-    true
+    moves
 }
 
 /*
 TRANSPOSITION TABLE
 */
 
+// Searches are numbered by generation. Entries untouched for more than
+// max_age generations are evicted at the start of the next search.
+const DEFAULT_MAX_AGE: u32 = 2;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Entry {
+    depth: u16,
+    prob: f32,
+    value: (Direction, f32),
+    // Last search that stored or used the entry
+    generation: u32,
+}
+
 #[derive(Serialize, Deserialize)]
-pub struct TranspositionTable {
-    table: HashMap<State, (u16, f32, (Direction, f32))>,
-    items: u64,
-    hits: u64,
-    misses: u64,
+pub struct TranspositionTable<B: Board = State> {
+    // Keyed by Zobrist key, see zobrist.rs
+    table: HashMap<u64, Entry, ZobristBuildHasher>,
+    // Key on the canonical board so symmetric positions share an entry
+    symmetric: bool,
+    generation: u32,
+    max_age: u32,
+    stats: TranspositionStats,
+    board: PhantomData<B>,
 }
 
-impl TranspositionTable {
-    pub fn new() -> TranspositionTable {
+impl<B: Board> TranspositionTable<B> {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> TranspositionTable<B> {
+        TranspositionTable {
+            table: HashMap::default(),
+            symmetric: false,
+            generation: 0,
+            max_age: DEFAULT_MAX_AGE,
+            stats: TranspositionStats::default(),
+            board: PhantomData,
+        }
+    }
+
+    pub fn with_symmetry() -> TranspositionTable<B> {
         TranspositionTable {
-            table: HashMap::new(),
-            items: 0,
-            hits: 0,
-            misses: 0,
+            symmetric: true,
+            ..TranspositionTable::new()
         }
     }
 
-    pub fn clear(&mut self) {
-        // self.print_stats();
+    // 0 keeps only entries used by the last search
+    pub fn set_max_age(&mut self, max_age: u32) {
+        self.max_age = max_age;
+    }
+
+    pub fn len(&self) -> usize {
+        self.table.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.table.is_empty()
+    }
+
+    pub fn print_stats(&self) {
+        println!("Transposition table stats: {}", self.stats);
+    }
+}
+
+impl<B: Board> Transposition<B> for TranspositionTable<B> {
+    fn clear(&mut self) {
         self.table.clear();
-        self.items = 0;
-        self.hits = 0;
-        self.misses = 0;
-    }
-
-    pub fn insert(&mut self, state: State, depth: u16, prob: f32, value: (Direction, f32)) {
-        self.items += 1;
-        self.table.insert(state, (depth, prob, value));
-    }
-
-    pub fn get(&mut self, state: &State, depth: u16, prob: f32) -> Option<&(Direction, f32)> {
-        let output = self.table.get(state);
-        match output {
-            Some((d, p, v)) => {
-                if *d >= depth && *p >= prob {
-                    self.hits += 1;
-                    Some(v)
-                } else {
-                    self.misses += 1;
-                    None
-                }
+        self.generation = 0;
+        self.stats = TranspositionStats::default();
+    }
+
+    fn stats(&self) -> TranspositionStats {
+        self.stats.clone()
+    }
+
+    // Entries from recent searches stay available to the next one
+    fn next_generation(&mut self) {
+        self.generation += 1;
+        let (generation, max_age) = (self.generation, self.max_age);
+        let before = self.table.len();
+        self.table
+            .retain(|_, entry| generation - entry.generation <= max_age + 1);
+        self.stats.evictions += (before - self.table.len()) as u64;
+        self.stats.record_size(self.table.len());
+    }
+
+    fn insert(&mut self, state: B, key: u64, depth: u16, prob: f32, value: (Direction, f32)) {
+        self.stats.record_insert(depth);
+        let (key, symmetry) = transposition_key(&state, key, self.symmetric);
+        let entry = Entry {
+            depth,
+            prob,
+            value: (symmetry.map_direction(value.0), value.1),
+            generation: self.generation,
+        };
+        self.table.insert(key, entry);
+        self.stats.record_size(self.table.len());
+    }
+
+    // Entries from earlier searches are reused if they were searched at least
+    // as deep and pruned no more than this lookup needs
+    fn get(&mut self, state: &B, key: u64, depth: u16, prob: f32) -> Option<(Direction, f32)> {
+        let (key, symmetry) = transposition_key(state, key, self.symmetric);
+        match self.table.get_mut(&key) {
+            Some(entry) if entry.depth >= depth && entry.prob >= prob => {
+                self.stats.record_hit();
+                entry.generation = self.generation;
+                let (direction, score) = entry.value;
+                Some((symmetry.inverse().map_direction(direction), score))
             }
-            None => {
-                self.misses += 1;
+            stored => {
+                let stored = stored.map(|entry| (entry.depth, entry.prob));
+                self.stats.record_miss(stored, depth, prob);
                 None
             }
         }
     }
-
-    pub fn print_stats(&self) {
-        println!(
-            "Transposition table stats: {} items, {} hits, {} misses",
-            self.items, self.hits, self.misses
-        );
-    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::events::{Merge, Slide};
+    use crate::game::Symmetry;
+    use crate::heuristic::board_heuristic;
 
     #[test]
-    fn test_move_state() {
-        let precomputed: &Precomputed = &load_precomputed();
+    fn test_table_format() {
+        let precomputed = &compute_precomputed();
+        let bytes = _encode_precomputed(precomputed);
+        assert_eq!(bytes.len(), TABLE_FILE_LEN);
+        let decoded = _decode_precomputed(&bytes).unwrap();
+        assert_eq!(decoded.move_left, precomputed.move_left);
+        assert_eq!(decoded.move_right, precomputed.move_right);
+        assert_eq!(decoded.merge_score, precomputed.merge_score);
+        assert_eq!(decoded.trace_left, precomputed.trace_left);
+        assert_eq!(decoded.trace_right, precomputed.trace_right);
+        assert_eq!(decoded.row_sum, precomputed.row_sum);
+        assert_eq!(decoded.row_monotonicity, precomputed.row_monotonicity);
+        assert_eq!(decoded.row_empty, precomputed.row_empty);
+        assert_eq!(decoded.row_merges, precomputed.row_merges);
+        assert_eq!(decoded.row_heuristic, precomputed.row_heuristic);
+        assert_eq!(decoded.col_up, precomputed.col_up);
+        assert_eq!(decoded.col_down, precomputed.col_down);
 
-        let mut state = State {
-            grid: [[3, 4, 5, 6], [0, 0, 5, 6], [1, 1, 1, 1], [0, 5, 5, 0]],
-        };
-        state = move_state(
-            state.row_ints(),
-            state.col_ints(),
-            Direction::Left,
-            precomputed,
+        let invalid = |bytes: &[u8]| _decode_precomputed(bytes).is_err();
+        let mut corrupt = bytes.clone();
+        corrupt[HEADER_LEN + 1000] ^= 1;
+        assert!(invalid(&corrupt));
+        let mut stale = bytes.clone();
+        stale[8] += 1;
+        assert!(invalid(&stale));
+        let mut other_rules = bytes.clone();
+        other_rules[12] ^= 1;
+        assert!(invalid(&other_rules));
+        assert!(invalid(&bytes[..bytes.len() - 1]));
+        assert!(invalid(b"{\"move_left\": [1, 2"));
+    }
+
+    #[test]
+    fn test_shared_tables() {
+        let handles: Vec<_> = (0..4)
+            .map(|_| std::thread::spawn(|| tables() as *const Precomputed as usize))
+            .collect();
+        for handle in handles {
+            assert_eq!(
+                handle.join().unwrap(),
+                tables() as *const Precomputed as usize
+            );
+        }
+        assert_eq!(tables().move_left, compute_precomputed().move_left);
+    }
+
+    #[test]
+    fn test_heuristic() {
+        let state = State::from_grid([[4, 3, 2, 1], [0, 1, 1, 0], [5, 0, 0, 5], [2, 2, 2, 2]]);
+        assert_eq!(get_heuristic(state), board_heuristic(&state));
+        assert_eq!(
+            get_row_features(state.row(1)),
+            LineFeatures::new(&[0, 1, 1, 0])
         );
+    }
+
+    #[test]
+    fn test_transposition_aging() {
+        let transposition: &mut TranspositionTable = &mut TranspositionTable::new();
+        transposition.set_max_age(1);
+        let old = State::from_grid([[1, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0]]);
+        let used = State::from_grid([[2, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0]]);
+        transposition.insert(old, old.zobrist(), 3, 1.0, (Direction::Left, 1.0));
+        transposition.insert(used, used.zobrist(), 3, 1.0, (Direction::Up, 2.0));
+
+        // A deeper entry from an earlier search answers a shallower lookup
+        transposition.next_generation();
         assert_eq!(
-            state.grid,
-            [[3, 4, 5, 6], [5, 6, 0, 0], [2, 2, 0, 0], [6, 0, 0, 0]]
+            transposition.get(&used, used.zobrist(), 2, 0.5),
+            Some((Direction::Up, 2.0))
         );
+        assert_eq!(transposition.get(&used, used.zobrist(), 4, 0.5), None);
+        assert_eq!(transposition.get(&used, used.zobrist(), 2, 1.5), None);
+
+        transposition.next_generation();
+        assert_eq!(transposition.len(), 2);
+        transposition.next_generation();
+        assert_eq!(transposition.len(), 1);
+        assert_eq!(transposition.get(&old, old.zobrist(), 1, 0.5), None);
+        transposition.next_generation();
+        assert!(transposition.is_empty());
+    }
 
-        state.grid = [[1, 1, 0, 0], [0, 2, 0, 2], [3, 3, 4, 0], [0, 0, 0, 0]];
-        state = move_state(
-            state.row_ints(),
-            state.col_ints(),
-            Direction::Right,
-            precomputed,
+    #[test]
+    fn test_export() {
+        let path = std::env::temp_dir()
+            .join(format!("rust_solver_tables_{}", std::process::id()))
+            .join(TABLE_FILE);
+        precompute_to(&path).unwrap();
+        let exported = _decode_precomputed(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(exported.move_left, tables().move_left);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_move_state() {
+        let mut state = State::from_grid([[3, 4, 5, 6], [0, 0, 5, 6], [1, 1, 1, 1], [0, 5, 5, 0]]);
+        state = move_state(state, Direction::Left);
+        assert_eq!(
+            state.to_grid(),
+            [[3, 4, 5, 6], [5, 6, 0, 0], [2, 2, 0, 0], [6, 0, 0, 0]]
         );
+
+        state = State::from_grid([[1, 1, 0, 0], [0, 2, 0, 2], [3, 3, 4, 0], [0, 0, 0, 0]]);
+        state = move_state(state, Direction::Right);
         assert_eq!(
-            state.grid,
+            state.to_grid(),
             [[0, 0, 0, 2], [0, 0, 0, 3], [0, 0, 4, 4], [0, 0, 0, 0]]
         );
 
-        state.grid = [[1, 1, 2, 0], [1, 2, 0, 4], [0, 2, 2, 4], [0, 2, 0, 0]];
-        state = move_state(
-            state.row_ints(),
-            state.col_ints(),
-            Direction::Up,
-            precomputed,
-        );
+        state = State::from_grid([[1, 1, 2, 0], [1, 2, 0, 4], [0, 2, 2, 4], [0, 2, 0, 0]]);
+        state = move_state(state, Direction::Up);
         assert_eq!(
-            state.grid,
+            state.to_grid(),
             [[2, 1, 3, 5], [0, 3, 0, 0], [0, 2, 0, 0], [0, 0, 0, 0]]
         );
 
-        state.grid = [[0, 1, 2, 3], [0, 1, 0, 3], [3, 2, 2, 3], [3, 0, 0, 4]];
-        state = move_state(
-            state.row_ints(),
-            state.col_ints(),
-            Direction::Down,
-            precomputed,
-        );
+        state = State::from_grid([[0, 1, 2, 3], [0, 1, 0, 3], [3, 2, 2, 3], [3, 0, 0, 4]]);
+        state = move_state(state, Direction::Down);
         assert_eq!(
-            state.grid,
+            state.to_grid(),
             [[0, 0, 0, 0], [0, 0, 0, 3], [0, 2, 0, 4], [4, 2, 3, 4]]
         );
+
+        // Column tables agree with moving the transposed rows
+        let precomputed = tables();
+        let mut board: u64 = 0x0123_4567_89AB_CDEF;
+        for _ in 0..1000 {
+            board = board.wrapping_mul(0x5851_F42D_4C95_7F2D).wrapping_add(1);
+            let state = State { board };
+            let transposed = state.transpose();
+            assert_eq!(
+                move_state(state, Direction::Up),
+                move_rows(transposed, &precomputed.move_left).transpose()
+            );
+            assert_eq!(
+                move_state(state, Direction::Down),
+                move_rows(transposed, &precomputed.move_right).transpose()
+            );
+        }
     }
 
     #[test]
-    fn test_get_possible_moves() {
-        let precomputed: &Precomputed = &load_precomputed();
+    fn test_move_state_max_exponent() {
+        // 32768 + 32768 does not fit in a 4-bit cell, so the pair stays put
+        let state = State::from_grid([[15, 15, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0]]);
+        assert_eq!(move_state(state, Direction::Left), state);
+        let moves = get_possible_moves(state);
+        assert_eq!(moves[0].0, Direction::Right);
+        assert_eq!(moves[1].0, Direction::Down);
+        assert_eq!(moves[2].0, Direction::Invalid);
+    }
 
-        let mut state = State {
-            grid: [[1, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0]],
-        };
-        let moves = get_possible_moves(state, precomputed);
+    #[test]
+    fn test_move_wide_state() {
+        let mut state =
+            WideState::from_grid([[15, 15, 0, 0], [16, 0, 16, 0], [0, 0, 0, 0], [31, 31, 1, 1]]);
+        state = move_wide_state(state, Direction::Left);
+        assert_eq!(
+            state.to_grid(),
+            [[16, 0, 0, 0], [17, 0, 0, 0], [0, 0, 0, 0], [31, 31, 2, 0]]
+        );
+        assert_eq!(state.to_narrow(), None);
+
+        state = WideState::from_grid([[16, 0, 0, 0], [16, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0]]);
+        state = move_wide_state(state, Direction::Down);
+        assert_eq!(state.index(0, 3), 17);
+
+        let moves = get_possible_wide_moves(state);
+        assert_eq!(moves[0].0, Direction::Right);
+        assert_eq!(moves[1].0, Direction::Up);
+        assert_eq!(moves[2].0, Direction::Invalid);
+    }
+
+    #[test]
+    fn test_get_move_score() {
+        let state = State::from_grid([[1, 1, 1, 0], [2, 2, 2, 2], [0, 0, 0, 0], [3, 0, 0, 0]]);
+        assert_eq!(get_move_score(state, Direction::Left), 4 + 8 + 8);
+        assert_eq!(get_move_score(state, Direction::Right), 4 + 8 + 8);
+        assert_eq!(get_move_score(state, Direction::Up), 0);
+        assert_eq!(get_move_score(state, Direction::Invalid), 0);
+
+        let state = State::transpose(&state);
+        assert_eq!(get_move_score(state, Direction::Down), 20);
+
+        // Capped tiles do not merge, so they earn nothing
+        let state = State::from_grid([[15, 15, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0]]);
+        assert_eq!(get_move_score(state, Direction::Left), 0);
+        let state = state.to_wide();
+        assert_eq!(get_wide_move_score(state, Direction::Left), 65536);
+    }
+
+    #[test]
+    fn test_move_state_with_events() {
+        let state = State::from_grid([[1, 1, 0, 2], [0, 0, 0, 3], [0, 0, 0, 0], [4, 0, 0, 0]]);
+        let result = move_state_with_events(state, Direction::Left);
+        assert_eq!(result.state, move_state(state, Direction::Left));
+        assert_eq!(
+            result.slides,
+            vec![
+                Slide {
+                    from: (3, 0),
+                    to: (1, 0),
+                    value: 2
+                },
+                Slide {
+                    from: (3, 1),
+                    to: (0, 1),
+                    value: 3
+                },
+            ]
+        );
+        assert_eq!(
+            result.merges,
+            vec![Merge {
+                from: [(0, 0), (1, 0)],
+                to: (0, 0),
+                value: 2,
+                score: 4
+            }]
+        );
+        assert_eq!(result.score, get_move_score(state, Direction::Left));
+
+        let result = move_state_with_events(state, Direction::Down);
+        assert_eq!(result.merges, vec![]);
+        let slides: Vec<_> = result
+            .slides
+            .iter()
+            .map(|s| (s.from, s.to, s.value))
+            .collect();
+        assert_eq!(
+            slides,
+            vec![
+                ((0, 0), (0, 2), 1),
+                ((1, 0), (1, 3), 1),
+                ((3, 0), (3, 2), 2),
+                ((3, 1), (3, 3), 3),
+            ]
+        );
+
+        let result = move_state_with_events(state, Direction::Invalid);
+        assert_eq!(result.state, state);
+        assert!(result.slides.is_empty() && result.merges.is_empty());
+    }
+
+    #[test]
+    fn test_get_possible_moves() {
+        let mut state = State::from_grid([[1, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0]]);
+        let moves = get_possible_moves(state);
         assert_eq!(moves[1].0, Direction::Down);
         assert_eq!(moves[2].0, Direction::Invalid);
 
-        state.grid = [[1, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 1]];
-        let moves = get_possible_moves(state, precomputed);
+        state = State::from_grid([[1, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 1]]);
+        let moves = get_possible_moves(state);
         assert_eq!(moves[3].0, Direction::Down);
 
-        state.grid = [[1, 2, 3, 4], [5, 6, 7, 8], [9, 10, 11, 12], [13, 14, 15, 1]];
-        let moves = get_possible_moves(state, precomputed);
+        state = State::from_grid([[1, 2, 3, 4], [5, 6, 7, 8], [9, 10, 11, 12], [13, 14, 15, 1]]);
+        let moves = get_possible_moves(state);
         assert_eq!(moves[0].0, Direction::Invalid);
     }
 
+    #[test]
+    fn test_transposition_symmetry() {
+        let state = State::from_grid([[1, 1, 0, 2], [0, 3, 0, 3], [2, 0, 0, 0], [4, 0, 1, 1]]);
+        let mirrored = state.transform(Symmetry::FlipHorizontal);
+
+        let mut transposition: TranspositionTable = TranspositionTable::new();
+        transposition.insert(state, state.zobrist(), 2, 1.0, (Direction::Left, 10.0));
+        assert_eq!(
+            transposition.get(&state, state.zobrist(), 2, 1.0),
+            Some((Direction::Left, 10.0))
+        );
+        assert_eq!(
+            transposition.get(&mirrored, mirrored.zobrist(), 2, 1.0),
+            None
+        );
+
+        let mut transposition: TranspositionTable = TranspositionTable::with_symmetry();
+        transposition.insert(state, state.zobrist(), 2, 1.0, (Direction::Left, 10.0));
+        assert_eq!(
+            transposition.get(&state, state.zobrist(), 2, 1.0),
+            Some((Direction::Left, 10.0))
+        );
+        assert_eq!(
+            transposition.get(&mirrored, mirrored.zobrist(), 2, 1.0),
+            Some((Direction::Right, 10.0))
+        );
+        for symmetry in Symmetry::ALL {
+            let transformed = state.transform(symmetry);
+            assert_eq!(
+                transposition.get(&transformed, transformed.zobrist(), 1, 0.5),
+                Some((symmetry.map_direction(Direction::Left), 10.0))
+            );
+        }
+        assert_eq!(
+            transposition.get(&mirrored, mirrored.zobrist(), 3, 1.0),
+            None
+        );
+    }
+
     #[test]
     fn test_is_game_over() {
-        let precomputed: &Precomputed = &load_precomputed();
+        let mut state = State::from_grid([[1, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0]]);
+        assert!(!is_game_over(state));
+        state = State::from_grid([[1, 2, 3, 4], [5, 6, 7, 8], [9, 10, 11, 12], [13, 14, 15, 1]]);
+        assert!(is_game_over(state));
+        // Neighbours across a row or column edge do not merge
+        state = State::from_grid([[1, 2, 1, 2], [2, 1, 2, 1], [1, 2, 1, 2], [2, 1, 2, 1]]);
+        assert!(is_game_over(state));
+        state.set_tile(3, 3, 2);
+        assert!(!is_game_over(state));
+        state.set_tile(3, 3, 0);
+        assert!(!is_game_over(state));
+        // The largest tiles do not merge
+        state = State::from_grid([[15, 15, 1, 2], [2, 1, 2, 1], [1, 2, 1, 2], [2, 1, 2, 1]]);
+        assert!(is_game_over(state));
+        state = State::from_grid([[15, 2, 1, 2], [15, 1, 2, 1], [1, 2, 1, 2], [2, 1, 2, 1]]);
+        assert!(is_game_over(state));
 
-        let mut state = State {
-            grid: [[1, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0]],
-        };
-        assert_eq!(is_game_over(state, precomputed), false);
-        state.grid = [[1, 2, 3, 4], [5, 6, 7, 8], [9, 10, 11, 12], [13, 14, 15, 1]];
-        assert_eq!(is_game_over(state, precomputed), true);
+        // Same as having no possible move, on any boards and on boards with
+        // few tile values, small or large
+        let mut board: u64 = 1;
+        for _ in 0..10000 {
+            board = board.wrapping_mul(0x5851_F42D_4C95_7F2D).wrapping_add(1);
+            let few = (board >> 1) & 0x7777_7777_7777_7777;
+            for board in [
+                board,
+                few | 0x1111_1111_1111_1111,
+                few | 0x8888_8888_8888_8888,
+            ] {
+                let state = State { board };
+                let over = get_possible_moves(state)[0].0 == Direction::Invalid;
+                assert_eq!(is_game_over(state), over);
+            }
+        }
     }
 }
@@ -1,12 +1,18 @@
-use super::game::{Direction, State};
-use super::precompute::Precomputed;
+use rand::Rng;
 
-#[allow(dead_code)]
-pub fn get_random_move(
-    _state: State,
-    moves: [(Direction, State); 4],
-    _precomputed: &Precomputed,
-) -> (Direction, State) {
-    let index = rand::random::<usize>() % moves.len();
+use super::board::Board;
+use super::game::Direction;
+
+// Legal moves come first in moves, padded with Direction::Invalid
+pub fn get_random_move<B: Board, R: Rng + ?Sized>(
+    _state: B,
+    moves: [(Direction, B); 4],
+    rng: &mut R,
+) -> (Direction, B) {
+    let legal = moves
+        .iter()
+        .take_while(|(direction, _)| *direction != Direction::Invalid)
+        .count();
+    let index = rng.gen_range(0..legal.max(1));
     moves[index]
 }
@@ -0,0 +1,170 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use super::board::Board;
+use super::error::SolverError;
+#[cfg(test)]
+use super::game::State;
+
+// NOTE: Values are exponents, so 1 is a 2 tile and 11 is the 2048 tile.
+// Fields are private so the spawns the search expands always match the ones
+// the game samples, see GameRules::new.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "RawRules")]
+pub struct GameRules {
+    // (value, probability) for each tile that can spawn, most likely first,
+    // probabilities adding up to 1
+    spawns: Vec<(u16, f32)>,
+    initial_tiles: u16,
+    target: u16,
+}
+
+// Rules as saved, checked by GameRules::new when loaded
+#[derive(Deserialize)]
+struct RawRules {
+    spawns: Vec<(u16, f32)>,
+    initial_tiles: u16,
+    target: u16,
+}
+
+impl TryFrom<RawRules> for GameRules {
+    type Error = SolverError;
+
+    fn try_from(raw: RawRules) -> Result<GameRules, SolverError> {
+        GameRules::new(raw.spawns, raw.initial_tiles, raw.target)
+    }
+}
+
+impl Default for GameRules {
+    fn default() -> GameRules {
+        GameRules {
+            spawns: vec![(1, 0.9), (2, 0.1)],
+            initial_tiles: 2,
+            target: 11,
+        }
+    }
+}
+
+impl GameRules {
+    // Spawn probabilities are weights, normalised to add up to 1 and sorted
+    // most likely first. Fails on no spawns, a zero tile or a weight that is
+    // not positive.
+    pub fn new(
+        spawns: Vec<(u16, f32)>,
+        initial_tiles: u16,
+        target: u16,
+    ) -> Result<GameRules, SolverError> {
+        let valid = |&(value, prob): &(u16, f32)| value > 0 && prob.is_finite() && prob > 0.0;
+        if spawns.is_empty() || !spawns.iter().all(valid) {
+            return Err(SolverError::InvalidArgument {
+                name: "spawns".to_string(),
+                value: format!("{:?}", spawns),
+            });
+        }
+        let total: f32 = spawns.iter().map(|&(_, prob)| prob).sum();
+        let mut spawns: Vec<(u16, f32)> = spawns
+            .into_iter()
+            .map(|(value, prob)| (value, prob / total))
+            .collect();
+        spawns.sort_by(|a, b| b.1.total_cmp(&a.1));
+        Ok(GameRules {
+            spawns,
+            initial_tiles,
+            target,
+        })
+    }
+
+    pub fn spawns(&self) -> &[(u16, f32)] {
+        &self.spawns
+    }
+
+    pub fn initial_tiles(&self) -> u16 {
+        self.initial_tiles
+    }
+
+    pub fn target(&self) -> u16 {
+        self.target
+    }
+
+    pub fn sample_spawn<R: Rng + ?Sized>(&self, rng: &mut R) -> u16 {
+        let mut roll = rng.gen::<f32>();
+        for &(value, prob) in &self.spawns {
+            if roll < prob {
+                return value;
+            }
+            roll -= prob;
+        }
+        self.spawns[self.spawns.len() - 1].0
+    }
+
+    pub fn reached_target<B: Board>(&self, state: &B) -> bool {
+        state.max_exponent() >= self.target
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_sample_spawn() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let rules = GameRules::default();
+        let fours = (0..10000)
+            .map(|_| rules.sample_spawn(&mut rng))
+            .filter(|value| *value == 2)
+            .count();
+        assert!(fours > 800 && fours < 1200);
+
+        let rules = GameRules::new(vec![(2, 1.0)], 2, 11).unwrap();
+        assert!((0..100).all(|_| rules.sample_spawn(&mut rng) == 2));
+    }
+
+    #[test]
+    fn test_new() {
+        // Weights are normalised and sorted, so the game and the search agree
+        let rules = GameRules::new(vec![(2, 0.3), (1, 0.5)], 2, 11).unwrap();
+        assert_eq!(rules.spawns(), &[(1, 0.625), (2, 0.375)]);
+        let mut rng = StdRng::seed_from_u64(0);
+        let fours = (0..10000)
+            .map(|_| rules.sample_spawn(&mut rng))
+            .filter(|value| *value == 2)
+            .count();
+        assert!(fours > 3500 && fours < 4000);
+
+        assert!(GameRules::new(vec![], 2, 11).is_err());
+        assert!(GameRules::new(vec![(1, 0.9), (2, 0.0)], 2, 11).is_err());
+        assert!(GameRules::new(vec![(1, -1.0)], 2, 11).is_err());
+        assert!(GameRules::new(vec![(1, f32::NAN)], 2, 11).is_err());
+        assert!(GameRules::new(vec![(0, 1.0)], 2, 11).is_err());
+
+        // Saved rules are checked the same way
+        let json = serde_json::to_string(&rules).unwrap();
+        assert_eq!(serde_json::from_str::<GameRules>(&json).unwrap(), rules);
+        let json = r#"{"spawns":[[2,1.0],[1,3.0]],"initial_tiles":2,"target":11}"#;
+        let loaded: GameRules = serde_json::from_str(json).unwrap();
+        assert_eq!(loaded.spawns(), &[(1, 0.75), (2, 0.25)]);
+        let json = r#"{"spawns":[],"initial_tiles":2,"target":11}"#;
+        assert!(serde_json::from_str::<GameRules>(json).is_err());
+    }
+
+    #[test]
+    fn test_initial_tiles() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let rules = GameRules::new(vec![(3, 1.0)], 4, 11).unwrap();
+        let state = State::new(&rules, &mut rng);
+        assert_eq!(state.count_empty(), 12);
+        assert_eq!(state.max_exponent(), 3);
+    }
+
+    #[test]
+    fn test_reached_target() {
+        let rules = GameRules::default();
+        let state = State::from_grid([[10, 10, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0]]);
+        assert!(!rules.reached_target(&state));
+        let state = State::from_grid([[11, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0]]);
+        assert!(rules.reached_target(&state));
+    }
+}
@@ -0,0 +1,247 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use super::board::Board;
+use super::game::{Direction, State};
+use super::rules::GameRules;
+
+/*
+GAME SESSION
+*/
+
+// One turn: the player's move followed by the tile that spawned
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ply {
+    pub direction: Direction,
+    pub spawn: (u16, u16),
+    pub value: u16,
+}
+
+// A game in progress. Every ply keeps the board and score it produced, so
+// undo, redo and jumping to any ply are O(1).
+// NOTE: history may extend past the current ply after an undo, until the next
+// move replaces the undone plies.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Game<B: Board = State> {
+    history: Vec<Ply>,
+    // (board, score) after each ply, snapshots[0] is the starting board
+    snapshots: Vec<(B, u64)>,
+    ply: usize,
+}
+
+impl<B: Board> Game<B> {
+    pub fn new(initial: B) -> Game<B> {
+        Game {
+            history: Vec::new(),
+            snapshots: vec![(initial, 0)],
+            ply: 0,
+        }
+    }
+
+    pub fn start<R: Rng + ?Sized>(rules: &GameRules, rng: &mut R) -> Game<B> {
+        Game::new(B::new(rules, rng))
+    }
+
+    pub fn state(&self) -> B {
+        self.snapshots[self.ply].0
+    }
+
+    pub fn score(&self) -> u64 {
+        self.snapshots[self.ply].1
+    }
+
+    pub fn move_count(&self) -> usize {
+        self.ply
+    }
+
+    // Plies played up to the current position
+    pub fn history(&self) -> &[Ply] {
+        &self.history[..self.ply]
+    }
+
+    pub fn is_over(&self) -> bool {
+        self.state().get_possible_moves()[0].0 == Direction::Invalid
+    }
+
+    // Replays a known ply. Returns false and leaves the game unchanged if the
+    // move does nothing, the spawn cell is off the board or not empty
+    // afterwards, or value is not a tile the board can hold.
+    pub fn play(&mut self, direction: Direction, spawn: (u16, u16), value: u16) -> bool {
+        if spawn.0 >= B::SIZE || spawn.1 >= B::SIZE || value == 0 || value > B::MAX_EXPONENT {
+            return false;
+        }
+        let moved = match self.apply_move(direction) {
+            Some(moved) => moved,
+            None => return false,
+        };
+        if moved.0.index(spawn.0, spawn.1) != 0 {
+            return false;
+        }
+        self.push(direction, spawn, value, moved);
+        true
+    }
+
+    // Makes a move and spawns a random tile. Returns false and leaves the game
+    // unchanged if the move does nothing.
+    pub fn play_random<R: Rng + ?Sized>(
+        &mut self,
+        direction: Direction,
+        rules: &GameRules,
+        rng: &mut R,
+    ) -> bool {
+        let moved = match self.apply_move(direction) {
+            Some(moved) => moved,
+            None => return false,
+        };
+        // A move always frees at least one cell, so there is room to spawn
+        let (spawn, value) = moved.0.random_spawn(rules, rng).unwrap();
+        self.push(direction, spawn, value, moved);
+        true
+    }
+
+    pub fn undo(&mut self) -> bool {
+        self.jump_to(self.ply.wrapping_sub(1))
+    }
+
+    pub fn redo(&mut self) -> bool {
+        self.jump_to(self.ply + 1)
+    }
+
+    // Moves to any ply in the history, 0 is the starting board
+    pub fn jump_to(&mut self, ply: usize) -> bool {
+        if ply >= self.snapshots.len() {
+            return false;
+        }
+        self.ply = ply;
+        true
+    }
+
+    fn apply_move(&self, direction: Direction) -> Option<(B, u64)> {
+        let (state, score) = self.snapshots[self.ply];
+        if direction == Direction::Invalid {
+            return None;
+        }
+        let result = state.move_with_events(direction);
+        if result.state == state {
+            return None;
+        }
+        Some((result.state, score + result.score))
+    }
+
+    fn push(&mut self, direction: Direction, spawn: (u16, u16), value: u16, moved: (B, u64)) {
+        let (mut state, score) = moved;
+        state.set_tile(spawn.0, spawn.1, value);
+        self.history.truncate(self.ply);
+        self.snapshots.truncate(self.ply + 1);
+        self.history.push(Ply {
+            direction,
+            spawn,
+            value,
+        });
+        self.snapshots.push((state, score));
+        self.ply += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::precompute::move_state;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_play() {
+        let initial = State::from_grid([[1, 1, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0]]);
+        let mut game = Game::new(initial);
+
+        assert!(!game.play(Direction::Up, (3, 3), 1));
+        assert!(!game.play(Direction::Left, (0, 0), 1));
+        assert!(!game.play(Direction::Left, (4, 3), 1));
+        assert!(!game.play(Direction::Left, (3, 4), 1));
+        assert!(!game.play(Direction::Left, (3, 3), 0));
+        assert!(!game.play(Direction::Left, (3, 3), 16));
+        assert_eq!(game.move_count(), 0);
+        assert_eq!(game.state(), initial);
+        assert!(game.play(Direction::Left, (3, 3), 2));
+        assert_eq!(
+            game.state(),
+            State::from_grid([[2, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 2]])
+        );
+        assert_eq!(game.score(), 4);
+        assert_eq!(game.move_count(), 1);
+        assert_eq!(
+            game.history(),
+            &[Ply {
+                direction: Direction::Left,
+                spawn: (3, 3),
+                value: 2
+            }]
+        );
+    }
+
+    #[test]
+    fn test_undo_redo() {
+        let rules = GameRules::default();
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut game: Game = Game::start(&rules, &mut rng);
+        let initial = game.state();
+        assert!(!game.undo());
+        assert!(!game.redo());
+
+        let mut states = vec![initial];
+        let mut scores = vec![0];
+        while states.len() < 20 {
+            let state = game.state();
+            let moves = state.get_possible_moves();
+            assert!(game.play_random(moves[0].0, &rules, &mut rng));
+            let moved = move_state(state, moves[0].0);
+            assert_eq!(moved.count_empty(), game.state().count_empty() + 1);
+            states.push(game.state());
+            scores.push(game.score());
+        }
+
+        assert!(game.undo());
+        assert!(game.undo());
+        assert_eq!(game.state(), states[17]);
+        assert_eq!(game.score(), scores[17]);
+        assert_eq!(game.history().len(), 17);
+        assert!(game.redo());
+        assert_eq!(game.state(), states[18]);
+
+        assert!(game.jump_to(0));
+        assert_eq!(game.state(), initial);
+        assert_eq!(game.score(), 0);
+        assert!(game.jump_to(19));
+        assert_eq!(game.state(), states[19]);
+        assert!(!game.jump_to(20));
+        assert!(!game.redo());
+
+        // Playing after an undo discards the undone plies
+        assert!(game.jump_to(5));
+        let moves = game.state().get_possible_moves();
+        assert!(game.play_random(moves[0].0, &rules, &mut rng));
+        assert_eq!(game.move_count(), 6);
+        assert!(!game.redo());
+        assert!(!game.jump_to(7));
+    }
+
+    #[test]
+    fn test_replay() {
+        let rules = GameRules::default();
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut game: Game = Game::start(&rules, &mut rng);
+        while !game.is_over() {
+            let moves = game.state().get_possible_moves();
+            let direction = moves[rng.gen_range(0..4)].0;
+            game.play_random(direction, &rules, &mut rng);
+        }
+
+        let mut replay = Game::new(game.snapshots[0].0);
+        for ply in game.history() {
+            assert!(replay.play(ply.direction, ply.spawn, ply.value));
+        }
+        assert_eq!(replay.state(), game.state());
+        assert_eq!(replay.score(), game.score());
+    }
+}
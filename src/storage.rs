@@ -0,0 +1,144 @@
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::error::SolverError;
+
+/*
+DATA LOCATION
+*/
+
+// Overrides the directory for saved tables and caches
+pub const DATA_DIR_VAR: &str = "RUST_SOLVER_DATA_DIR";
+
+const APP_DIR: &str = "rust_solver";
+
+// RUST_SOLVER_DATA_DIR if set, otherwise the per-user cache directory,
+// otherwise the working directory
+pub fn data_dir() -> PathBuf {
+    _data_dir(|name| std::env::var_os(name).map(PathBuf::from))
+}
+
+fn _data_dir<F: Fn(&str) -> Option<PathBuf>>(var: F) -> PathBuf {
+    if let Some(dir) = var(DATA_DIR_VAR).filter(|dir| !dir.as_os_str().is_empty()) {
+        return dir;
+    }
+    let cache_dir = if cfg!(windows) {
+        var("LOCALAPPDATA")
+    } else if cfg!(target_os = "macos") {
+        var("HOME").map(|home| home.join("Library").join("Caches"))
+    } else {
+        var("XDG_CACHE_HOME")
+            .filter(|dir| dir.is_absolute())
+            .or_else(|| var("HOME").map(|home| home.join(".cache")))
+    };
+    match cache_dir {
+        Some(dir) => dir.join(APP_DIR),
+        None => PathBuf::from("."),
+    }
+}
+
+// Path of a saved file, file_name joined to the data directory
+pub fn data_path(file_name: &str) -> PathBuf {
+    data_dir().join(file_name)
+}
+
+/*
+ATOMIC WRITES
+*/
+
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// Writes to a temp file next to path, then renames it over path, so readers
+// and concurrent writers only ever see a complete file
+pub fn write_atomic(path: &Path, bytes: &[u8]) -> Result<(), SolverError> {
+    write_atomic_with(path, |file| file.write_all(bytes))
+}
+
+// Same as write_atomic, for output too large to build in memory first
+pub fn write_atomic_with<F>(path: &Path, write: F) -> Result<(), SolverError>
+where
+    F: FnOnce(&mut BufWriter<fs::File>) -> io::Result<()>,
+{
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(format!(
+        ".{}.{}.tmp",
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let temp_path = path.with_file_name(temp_name);
+
+    let result = fs::File::create(&temp_path)
+        .and_then(|file| {
+            let mut file = BufWriter::new(file);
+            write(&mut file)?;
+            file.into_inner()
+                .map_err(|err| err.into_error())?
+                .sync_all()
+        })
+        .and_then(|_| fs::rename(&temp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    Ok(result?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_data_dir() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(key, _)| *key == name)
+                    .map(|(_, value)| PathBuf::from(value))
+            }
+        };
+        assert_eq!(
+            _data_dir(env(&[(DATA_DIR_VAR, "/data"), ("HOME", "/home/me")])),
+            PathBuf::from("/data")
+        );
+        assert_eq!(_data_dir(env(&[])), PathBuf::from("."));
+        if cfg!(all(unix, not(target_os = "macos"))) {
+            assert_eq!(
+                _data_dir(env(&[(DATA_DIR_VAR, ""), ("HOME", "/home/me")])),
+                PathBuf::from("/home/me/.cache/rust_solver")
+            );
+            assert_eq!(
+                _data_dir(env(&[("XDG_CACHE_HOME", "/cache"), ("HOME", "/home/me")])),
+                PathBuf::from("/cache/rust_solver")
+            );
+        }
+    }
+
+    #[test]
+    fn test_write_atomic() {
+        let dir = std::env::temp_dir().join(format!("rust_solver_{}", std::process::id()));
+        let path = dir.join("nested").join("table.bin");
+        write_atomic(&path, b"first").unwrap();
+        write_atomic(&path, b"second").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+
+        let writers: Vec<_> = (0..8u8)
+            .map(|i| {
+                let path = path.clone();
+                std::thread::spawn(move || write_atomic(&path, &[i; 4096]).unwrap())
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        let bytes = fs::read(&path).unwrap();
+        assert_eq!(bytes.len(), 4096);
+        assert!(bytes.iter().all(|byte| *byte == bytes[0]));
+        // Only the final file is left behind
+        assert_eq!(fs::read_dir(path.parent().unwrap()).unwrap().count(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
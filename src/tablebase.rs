@@ -0,0 +1,388 @@
+use super::error::SolverError;
+use super::expectimax::lost_score;
+use super::game::{Direction, State};
+use super::heuristic::heuristic_base;
+use super::precompute::{get_possible_moves, TranspositionTable};
+use super::rules::GameRules;
+use super::transposition::{Transposition, TranspositionStats};
+
+/*
+ENDGAME TABLEBASE
+*/
+
+// NOTE: A tablebase solves a restricted game: the non-empty cells of the
+// locked board must keep their tiles, and the game is won once a free cell
+// holds the target tile. Moves that would disturb a locked cell are not
+// allowed, so every probability is exact for the restricted game and a lower
+// bound for the real one.
+
+// Largest number of free cell assignments a tablebase may have. Each takes 5
+// bytes once built and 9 while generating, about 1.3 GB and 2.4 GB at most.
+const MAX_POSITIONS: usize = 1 << 28;
+
+pub struct Tablebase {
+    locked: State,
+    // 0xF in every locked cell
+    locked_mask: u64,
+    // Bit offset of each free cell
+    free_cells: Vec<u32>,
+    target: u16,
+    // Best move and probability of reaching the target, indexed by the free
+    // cells read as a base target number
+    moves: Vec<Direction>,
+    probs: Vec<f32>,
+}
+
+impl Tablebase {
+    // Solves every position that matches locked, with tiles below target in
+    // its empty cells. Each turn adds a tile, so the tile sum only grows and
+    // positions can be solved from the largest sum down.
+    pub fn generate(
+        locked: State,
+        target: u16,
+        rules: &GameRules,
+    ) -> Result<Tablebase, SolverError> {
+        let free_cells: Vec<u32> = (0..16)
+            .map(|cell| 60 - 4 * cell)
+            .filter(|&shift| (locked.board >> shift) & 0xF == 0)
+            .collect();
+        let positions = match (target as usize).checked_pow(free_cells.len() as u32) {
+            Some(count) if (2..=15).contains(&target) && count <= MAX_POSITIONS => count,
+            _ => {
+                return Err(SolverError::InvalidArgument {
+                    name: "tablebase".to_string(),
+                    value: format!("{} free cells below tile {}", free_cells.len(), target),
+                })
+            }
+        };
+        let locked_mask = free_cells
+            .iter()
+            .fold(u64::MAX, |mask, &shift| mask & !(0xF << shift));
+        let mut tablebase = Tablebase {
+            locked,
+            locked_mask,
+            free_cells,
+            target,
+            moves: vec![Direction::Invalid; positions],
+            probs: vec![0.0; positions],
+        };
+
+        // Counting sort by tile sum, largest first. Sums are cheap, so they are
+        // computed twice rather than stored.
+        let sum = |index: usize| -> usize {
+            let state = tablebase.position(index);
+            tablebase
+                .free_cells
+                .iter()
+                .map(|&shift| match (state.board >> shift) & 0xF {
+                    0 => 0,
+                    value => 1 << value,
+                })
+                .sum()
+        };
+        // Every free cell holding the largest tile below target
+        let max_sum = tablebase.free_cells.len() << (target - 1);
+        let mut starts = vec![0; max_sum + 2];
+        for index in 0..positions {
+            starts[max_sum - sum(index) + 1] += 1;
+        }
+        for i in 1..starts.len() {
+            starts[i] += starts[i - 1];
+        }
+        let mut order = vec![0u32; positions];
+        for index in 0..positions {
+            let start = &mut starts[max_sum - sum(index)];
+            order[*start] = index as u32;
+            *start += 1;
+        }
+
+        for index in order {
+            let index = index as usize;
+            let (direction, prob) = tablebase.solve(tablebase.position(index), rules);
+            tablebase.moves[index] = direction;
+            tablebase.probs[index] = prob;
+        }
+        Ok(tablebase)
+    }
+
+    pub fn len(&self) -> usize {
+        self.probs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.probs.is_empty()
+    }
+
+    pub fn target(&self) -> u16 {
+        self.target
+    }
+
+    fn position(&self, mut index: usize) -> State {
+        let mut board = self.locked.board;
+        for &shift in &self.free_cells {
+            board |= ((index % self.target as usize) as u64) << shift;
+            index /= self.target as usize;
+        }
+        State { board }
+    }
+
+    // None if a free cell holds the target or more
+    fn index(&self, state: State) -> Option<usize> {
+        let mut index = 0;
+        for &shift in self.free_cells.iter().rev() {
+            let value = (state.board >> shift) & 0xF;
+            if value >= self.target as u64 {
+                return None;
+            }
+            index = index * self.target as usize + value as usize;
+        }
+        Some(index)
+    }
+
+    fn is_locked(&self, state: State) -> bool {
+        state.board & self.locked_mask == self.locked.board
+    }
+
+    // Best restricted move, children are already solved
+    fn solve(&self, state: State, rules: &GameRules) -> (Direction, f32) {
+        let mut best = (Direction::Invalid, 0.0);
+        for (direction, next_state) in get_possible_moves(state) {
+            if direction == Direction::Invalid || !self.is_locked(next_state) {
+                continue;
+            }
+            // Locked cells hold tiles, so every empty cell is free
+            let empty_tiles = next_state.get_empty_tiles();
+            let frac = 1.0 / empty_tiles.len() as f32;
+            let prob = match self.index(next_state) {
+                None => 1.0,
+                Some(_) => {
+                    let mut prob = 0.0;
+                    for &(x, y) in &empty_tiles {
+                        for &(value, spawn_prob) in rules.spawns() {
+                            let mut spawned = next_state;
+                            spawned.set_tile(x, y, value);
+                            let child = self.index(spawned).map_or(1.0, |index| self.probs[index]);
+                            prob += frac * spawn_prob * child;
+                        }
+                    }
+                    prob
+                }
+            };
+            if best.0 == Direction::Invalid || prob > best.1 {
+                best = (direction, prob);
+            }
+        }
+        best
+    }
+
+    // Best restricted move and the exact probability of reaching the target
+    // with it, None if state is not covered. Invalid if no restricted move.
+    pub fn get(&self, state: State) -> Option<(Direction, f32)> {
+        if !self.is_locked(state) {
+            return None;
+        }
+        let index = self.index(state)?;
+        Some((self.moves[index], self.probs[index]))
+    }
+}
+
+/*
+SEARCH INTEGRATION
+*/
+
+// Transposition table that answers covered positions from a tablebase and
+// defers everything else to table. Covered positions are valued in heuristic
+// units, from a lost game at probability 0 to win_value at probability 1, so
+// they compare with the leaves and losses around them.
+pub struct TablebaseTransposition<T: Transposition<State> = TranspositionTable> {
+    tablebase: Tablebase,
+    table: T,
+    win_value: f32,
+    lost_value: f32,
+}
+
+impl<T: Transposition<State>> TablebaseTransposition<T> {
+    // Reaching the target is worth about the best heuristic value, so the
+    // search only leaves the restricted game when it is unlikely to win
+    pub fn new(tablebase: Tablebase, table: T) -> TablebaseTransposition<T> {
+        TablebaseTransposition {
+            tablebase,
+            table,
+            win_value: heuristic_base(4),
+            lost_value: lost_score::<State>(),
+        }
+    }
+
+    pub fn set_win_value(&mut self, win_value: f32) {
+        self.win_value = win_value;
+    }
+
+    pub fn tablebase(&self) -> &Tablebase {
+        &self.tablebase
+    }
+}
+
+impl<T: Transposition<State>> Transposition<State> for TablebaseTransposition<T> {
+    fn get(&mut self, state: &State, key: u64, depth: u16, prob: f32) -> Option<(Direction, f32)> {
+        self.table.get(state, key, depth, prob)
+    }
+
+    fn insert(&mut self, state: State, key: u64, depth: u16, prob: f32, value: (Direction, f32)) {
+        self.table.insert(state, key, depth, prob, value);
+    }
+
+    fn next_generation(&mut self) {
+        self.table.next_generation();
+    }
+
+    fn clear(&mut self) {
+        self.table.clear();
+    }
+
+    // Positions without a restricted move are left to the search, which may
+    // still break the lock
+    fn solved(&self, state: &State) -> Option<(Direction, f32)> {
+        match self.tablebase.get(*state) {
+            Some((direction, prob)) if direction != Direction::Invalid => {
+                let value = self.lost_value + prob * (self.win_value - self.lost_value);
+                Some((direction, value))
+            }
+            _ => None,
+        }
+    }
+
+    fn stats(&self) -> TranspositionStats {
+        self.table.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::Board;
+    use crate::expectimax::{get_expectimax_move, lost_score};
+    use std::collections::HashMap;
+
+    // Top row free, the rest full of tiles that can neither move nor merge
+    fn locked() -> State {
+        State::from_grid([[0, 0, 0, 0], [15, 14, 13, 12], [8, 9, 10, 11], [7, 6, 5, 4]])
+    }
+
+    // Plain recursive expectimax over the restricted game
+    fn brute_force(
+        tablebase: &Tablebase,
+        state: State,
+        rules: &GameRules,
+        memo: &mut HashMap<u64, f32>,
+    ) -> f32 {
+        if tablebase.index(state).is_none() {
+            return 1.0;
+        }
+        if let Some(&prob) = memo.get(&state.board) {
+            return prob;
+        }
+        let mut best: f32 = 0.0;
+        for (direction, next_state) in get_possible_moves(state) {
+            if direction == Direction::Invalid || !tablebase.is_locked(next_state) {
+                continue;
+            }
+            let empty_tiles = next_state.get_empty_tiles();
+            let mut prob = 0.0;
+            for &(x, y) in &empty_tiles {
+                for &(value, spawn_prob) in rules.spawns() {
+                    let mut spawned = next_state;
+                    spawned.set_tile(x, y, value);
+                    prob += spawn_prob * brute_force(tablebase, spawned, rules, memo)
+                        / empty_tiles.len() as f32;
+                }
+            }
+            best = best.max(prob);
+        }
+        memo.insert(state.board, best);
+        best
+    }
+
+    #[test]
+    fn test_generate() {
+        let rules = &GameRules::default();
+        let tablebase = Tablebase::generate(locked(), 4, rules).unwrap();
+        assert_eq!(tablebase.len(), 4usize.pow(4));
+        let memo = &mut HashMap::new();
+        for index in 0..tablebase.len() {
+            let state = tablebase.position(index);
+            let (_, prob) = tablebase.get(state).unwrap();
+            assert!((prob - brute_force(&tablebase, state, rules, memo)).abs() < 1e-5);
+        }
+
+        let mut win = locked();
+        win.set_tile(0, 0, 3);
+        win.set_tile(1, 0, 3);
+        assert!(
+            matches!(tablebase.get(win), Some((Direction::Left | Direction::Right, prob)) if prob == 1.0)
+        );
+        let mut stuck = locked();
+        (0..4).for_each(|x| stuck.set_tile(x, 0, [1, 2, 1, 2][x as usize]));
+        assert_eq!(tablebase.get(stuck), Some((Direction::Invalid, 0.0)));
+        // Not covered: a tile at the target, or a locked cell changed
+        let mut won = locked();
+        won.set_tile(0, 0, 4);
+        assert_eq!(tablebase.get(won), None);
+        let mut moved = locked();
+        moved.set_tile(0, 1, 0);
+        assert_eq!(tablebase.get(moved), None);
+
+        assert!(Tablebase::generate(State { board: 0 }, 15, rules).is_err());
+    }
+
+    #[test]
+    fn test_solved_search() {
+        let rules = &GameRules::default();
+        let tablebase = Tablebase::generate(locked(), 4, rules).unwrap();
+        let transposition = &mut TablebaseTransposition::new(tablebase, TranspositionTable::new());
+
+        let mut state = locked();
+        state.set_tile(0, 0, 3);
+        state.set_tile(2, 0, 3);
+        let moves = state.get_possible_moves();
+        let (direction, _) =
+            get_expectimax_move(state, moves, 3, rules, transposition, None).unwrap();
+        assert!(matches!(direction, Direction::Left | Direction::Right));
+        // Covered positions never reach the table
+        assert_eq!(transposition.stats().lookups(), 0);
+    }
+
+    #[test]
+    fn test_solved_scale() {
+        let rules = &GameRules::default();
+        // Two free cells can never hold a 16, as a 3 cannot be built next to
+        // another 3, but there is always a restricted move
+        let locked =
+            State::from_grid([[0, 0, 13, 12], [15, 14, 10, 11], [8, 9, 5, 4], [7, 6, 2, 3]]);
+        let tablebase = Tablebase::generate(locked, 4, rules).unwrap();
+        let transposition = &mut TablebaseTransposition::new(tablebase, TranspositionTable::new());
+        let mut doomed = locked;
+        doomed.set_tile(0, 0, 3);
+        assert_eq!(
+            transposition.tablebase().get(doomed),
+            Some((Direction::Right, 0.0))
+        );
+        assert_eq!(
+            transposition.solved(&doomed),
+            Some((Direction::Right, lost_score::<State>()))
+        );
+
+        // Down drops the left columns onto the locked board, Left leaves it.
+        // A restricted game that cannot win is worth a loss, below any board.
+        let state =
+            State::from_grid([[15, 14, 13, 12], [8, 9, 10, 11], [7, 6, 5, 4], [0, 0, 2, 3]]);
+        assert_eq!(transposition.solved(&state), None);
+        let moves = state.get_possible_moves();
+        assert!(moves
+            .iter()
+            .any(|&(direction, next_state)| direction == Direction::Down
+                && next_state.board & !0xFF00_0000_0000_0000 == locked.board));
+        let (direction, _) =
+            get_expectimax_move(state, moves, 1, rules, transposition, None).unwrap();
+        assert_eq!(direction, Direction::Left);
+    }
+}
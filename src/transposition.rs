@@ -0,0 +1,493 @@
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::AddAssign;
+
+use serde::{Deserialize, Serialize};
+
+use super::board::Board;
+use super::game::{Direction, State, Symmetry};
+
+/*
+TRANSPOSITION INTERFACE
+*/
+
+// Anything the search can cache its results in, see precompute::TranspositionTable.
+// key is state.zobrist(), which the search keeps up to date incrementally.
+// prob is the node's probability over the search's pruning cutoff, so results
+// from searches with different cutoffs compare by how much was pruned.
+pub trait Transposition<B: Board> {
+    // Result for state if it was searched at least depth deep with at least prob
+    fn get(&mut self, state: &B, key: u64, depth: u16, prob: f32) -> Option<(Direction, f32)>;
+
+    fn insert(&mut self, state: B, key: u64, depth: u16, prob: f32, value: (Direction, f32));
+
+    // Called between searches, entries may survive into the next one
+    fn next_generation(&mut self);
+
+    // Resets the contents and the statistics
+    fn clear(&mut self);
+
+    // Exact result for a solved position, e.g. from a tablebase, checked
+    // before the depth limit so it also replaces leaf evaluations
+    fn solved(&self, _state: &B) -> Option<(Direction, f32)> {
+        None
+    }
+
+    // Counters since the table was created or last cleared, so over a whole
+    // game when the table is kept between moves
+    fn stats(&self) -> TranspositionStats;
+}
+
+// Key to store under and the symmetry that maps state onto it. Symmetric
+// tables key on the canonical board, whose key has to be computed afresh.
+pub fn transposition_key<B: Board>(state: &B, key: u64, symmetric: bool) -> (u64, Symmetry) {
+    if symmetric {
+        let (canonical, symmetry) = state.canonical();
+        (canonical.zobrist(), symmetry)
+    } else {
+        (key, Symmetry::Identity)
+    }
+}
+
+/*
+STATISTICS
+*/
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TranspositionStats {
+    pub hits: u64,
+    pub misses: u64,
+    // Misses where the position was stored, but searched too shallow or
+    // pruned at a higher probability than the lookup allows
+    pub shallow_rejections: u64,
+    pub prob_rejections: u64,
+    pub inserts: u64,
+    // Inserts indexed by remaining search depth, counting every insert rather
+    // than the entries still held, so replaced results are counted too
+    pub inserts_by_depth: Vec<u64>,
+    // Entries aged out, or overwritten by a colliding position
+    pub evictions: u64,
+    pub size: u64,
+    pub peak_size: u64,
+}
+
+impl TranspositionStats {
+    pub fn lookups(&self) -> u64 {
+        self.hits + self.misses
+    }
+
+    pub fn hit_rate(&self) -> f64 {
+        match self.lookups() {
+            0 => 0.0,
+            lookups => self.hits as f64 / lookups as f64,
+        }
+    }
+
+    pub fn record_hit(&mut self) {
+        self.hits += 1;
+    }
+
+    // stored is the depth and prob of the entry found under the key, if any
+    pub fn record_miss(&mut self, stored: Option<(u16, f32)>, depth: u16, prob: f32) {
+        self.misses += 1;
+        match stored {
+            Some((stored_depth, _)) if stored_depth < depth => self.shallow_rejections += 1,
+            Some((_, stored_prob)) if stored_prob < prob => self.prob_rejections += 1,
+            _ => {}
+        }
+    }
+
+    pub fn record_insert(&mut self, depth: u16) {
+        self.inserts += 1;
+        let depth = depth as usize;
+        if self.inserts_by_depth.len() <= depth {
+            self.inserts_by_depth.resize(depth + 1, 0);
+        }
+        self.inserts_by_depth[depth] += 1;
+    }
+
+    pub fn record_size(&mut self, size: usize) {
+        self.size = size as u64;
+        self.peak_size = self.peak_size.max(self.size);
+    }
+}
+
+// Combines the stats of several tables or games. Counters add up, and so does
+// size, the entries held by all the tables together. peak_size is the largest
+// single peak, as the tables need not peak at the same time.
+impl AddAssign<&TranspositionStats> for TranspositionStats {
+    fn add_assign(&mut self, other: &TranspositionStats) {
+        self.hits += other.hits;
+        self.misses += other.misses;
+        self.shallow_rejections += other.shallow_rejections;
+        self.prob_rejections += other.prob_rejections;
+        self.inserts += other.inserts;
+        if self.inserts_by_depth.len() < other.inserts_by_depth.len() {
+            self.inserts_by_depth
+                .resize(other.inserts_by_depth.len(), 0);
+        }
+        for (count, other_count) in self
+            .inserts_by_depth
+            .iter_mut()
+            .zip(&other.inserts_by_depth)
+        {
+            *count += other_count;
+        }
+        self.evictions += other.evictions;
+        self.size += other.size;
+        self.peak_size = self.peak_size.max(other.peak_size);
+    }
+}
+
+impl fmt::Display for TranspositionStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} entries (peak {}), {} inserts, {} hits, {} misses ({:.1}% hit rate), \
+             {} too shallow, {} pruned, {} evicted",
+            self.size,
+            self.peak_size,
+            self.inserts,
+            self.hits,
+            self.misses,
+            100.0 * self.hit_rate(),
+            self.shallow_rejections,
+            self.prob_rejections,
+            self.evictions
+        )
+    }
+}
+
+/*
+FIXED-CAPACITY TABLE
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Replacement {
+    // Keep the deeper result, unless the slot is from an older search
+    DepthPreferred,
+    // The newest result always wins
+    AlwaysReplace,
+    // Buckets of two: a depth-preferred slot and an always-replace slot
+    TwoTier,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+    key: u64,
+    depth: u16,
+    prob: f32,
+    value: (Direction, f32),
+    generation: u32,
+}
+
+// Power-of-two array of slots indexed by Zobrist key, sized from a memory
+// budget. Unlike TranspositionTable it never grows, colliding results
+// overwrite each other according to the replacement scheme.
+pub struct FixedTranspositionTable<B: Board = State> {
+    slots: Vec<Option<Slot>>,
+    mask: usize,
+    replacement: Replacement,
+    symmetric: bool,
+    generation: u32,
+    stats: TranspositionStats,
+    board: PhantomData<B>,
+}
+
+impl<B: Board> FixedTranspositionTable<B> {
+    pub fn new(megabytes: usize, replacement: Replacement) -> FixedTranspositionTable<B> {
+        let slot_size = std::mem::size_of::<Option<Slot>>();
+        // Largest power of two that fits, at least one bucket
+        let budget = (megabytes << 20) / slot_size;
+        let capacity = if budget < 2 {
+            2
+        } else {
+            1 << (usize::BITS - 1 - budget.leading_zeros())
+        };
+        FixedTranspositionTable {
+            slots: vec![None; capacity],
+            mask: capacity - 1,
+            replacement,
+            symmetric: false,
+            generation: 0,
+            stats: TranspositionStats::default(),
+            board: PhantomData,
+        }
+    }
+
+    pub fn with_symmetry(megabytes: usize, replacement: Replacement) -> FixedTranspositionTable<B> {
+        FixedTranspositionTable {
+            symmetric: true,
+            ..FixedTranspositionTable::new(megabytes, replacement)
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.slots.len() * std::mem::size_of::<Option<Slot>>()
+    }
+
+    pub fn len(&self) -> usize {
+        self.stats.size as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Slots a key may live in
+    fn bucket(&self, key: u64) -> std::ops::Range<usize> {
+        let index = key as usize & self.mask;
+        match self.replacement {
+            Replacement::TwoTier => (index & !1)..(index & !1) + 2,
+            _ => index..index + 1,
+        }
+    }
+
+    // Slot the new result goes to, None to drop it
+    fn choose_slot(&self, key: u64, depth: u16) -> Option<usize> {
+        let bucket = self.bucket(key);
+        if let Some(index) = bucket
+            .clone()
+            .find(|&index| matches!(self.slots[index], Some(slot) if slot.key == key))
+        {
+            return Some(index);
+        }
+        let replaceable = |index: usize| match self.slots[index] {
+            None => true,
+            Some(slot) => slot.generation != self.generation || depth >= slot.depth,
+        };
+        match self.replacement {
+            Replacement::AlwaysReplace => Some(bucket.start),
+            Replacement::DepthPreferred => Some(bucket.start).filter(|&index| replaceable(index)),
+            Replacement::TwoTier if replaceable(bucket.start) => Some(bucket.start),
+            Replacement::TwoTier => Some(bucket.start + 1),
+        }
+    }
+
+    pub fn print_stats(&self) {
+        println!(
+            "Fixed transposition table stats: {} slots, {}",
+            self.capacity(),
+            self.stats
+        );
+    }
+}
+
+impl<B: Board> Transposition<B> for FixedTranspositionTable<B> {
+    fn get(&mut self, state: &B, key: u64, depth: u16, prob: f32) -> Option<(Direction, f32)> {
+        let (key, symmetry) = transposition_key(state, key, self.symmetric);
+        let mut stored = None;
+        for index in self.bucket(key) {
+            if let Some(slot) = &mut self.slots[index] {
+                if slot.key != key {
+                    continue;
+                }
+                if slot.depth >= depth && slot.prob >= prob {
+                    self.stats.record_hit();
+                    slot.generation = self.generation;
+                    let (direction, score) = slot.value;
+                    return Some((symmetry.inverse().map_direction(direction), score));
+                }
+                stored = Some((slot.depth, slot.prob));
+            }
+        }
+        self.stats.record_miss(stored, depth, prob);
+        None
+    }
+
+    fn insert(&mut self, state: B, key: u64, depth: u16, prob: f32, value: (Direction, f32)) {
+        self.stats.record_insert(depth);
+        let (key, symmetry) = transposition_key(&state, key, self.symmetric);
+        let index = match self.choose_slot(key, depth) {
+            Some(index) => index,
+            None => return,
+        };
+        let mut size = self.stats.size;
+        match self.slots[index] {
+            None => size += 1,
+            Some(slot) if slot.key != key => {
+                if self.replacement == Replacement::TwoTier && index & 1 == 0 {
+                    // The displaced deep result falls back to the always-replace slot
+                    match self.slots[index + 1].replace(slot) {
+                        None => size += 1,
+                        Some(_) => self.stats.evictions += 1,
+                    }
+                } else {
+                    self.stats.evictions += 1;
+                }
+            }
+            Some(_) => {}
+        }
+        self.stats.record_size(size as usize);
+        self.slots[index] = Some(Slot {
+            key,
+            depth,
+            prob,
+            value: (symmetry.map_direction(value.0), value.1),
+            generation: self.generation,
+        });
+    }
+
+    // Slots are aged implicitly: results from older searches lose their
+    // depth priority and are overwritten first
+    fn next_generation(&mut self) {
+        self.generation += 1;
+    }
+
+    fn clear(&mut self) {
+        self.slots.iter_mut().for_each(|slot| *slot = None);
+        self.generation = 0;
+        self.stats = TranspositionStats::default();
+    }
+
+    fn stats(&self) -> TranspositionStats {
+        self.stats.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn board(value: u64) -> State {
+        State { board: value }
+    }
+
+    // Boards that share a slot in a table of the given capacity
+    fn colliding(capacity: usize, count: usize) -> Vec<State> {
+        let target = board(1).zobrist() as usize & (capacity - 1);
+        (1..)
+            .map(board)
+            .filter(|state| state.zobrist() as usize & (capacity - 1) == target)
+            .take(count)
+            .collect()
+    }
+
+    #[test]
+    fn test_capacity() {
+        let table: FixedTranspositionTable = FixedTranspositionTable::new(1, Replacement::TwoTier);
+        assert!(table.capacity().is_power_of_two());
+        assert!(table.memory_usage() <= 1 << 20);
+        assert!(table.memory_usage() * 2 > 1 << 20);
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    fn test_get_insert() {
+        let table = &mut FixedTranspositionTable::with_symmetry(1, Replacement::DepthPreferred);
+        let state = State::from_grid([[1, 2, 0, 0], [0, 0, 0, 0], [0, 0, 0, 3], [0, 0, 0, 0]]);
+        table.insert(state, state.zobrist(), 3, 0.5, (Direction::Left, 10.0));
+        assert_eq!(
+            table.get(&state, state.zobrist(), 3, 0.5),
+            Some((Direction::Left, 10.0))
+        );
+        assert_eq!(
+            table.get(&state, state.zobrist(), 2, 0.25),
+            Some((Direction::Left, 10.0))
+        );
+        assert_eq!(table.get(&state, state.zobrist(), 4, 0.5), None);
+        // Symmetric boards share the entry, with the move mapped back
+        let flipped = state.transform(Symmetry::FlipHorizontal);
+        assert_eq!(
+            table.get(&flipped, flipped.zobrist(), 3, 0.5),
+            Some((Direction::Right, 10.0))
+        );
+        table.clear();
+        assert_eq!(table.get(&state, state.zobrist(), 3, 0.5), None);
+    }
+
+    #[test]
+    fn test_stats() {
+        let table = &mut FixedTranspositionTable::new(1, Replacement::DepthPreferred);
+        let state = State::from_grid([[1, 2, 0, 0], [0, 0, 0, 0], [0, 0, 0, 3], [0, 0, 0, 0]]);
+        table.insert(state, state.zobrist(), 3, 0.5, (Direction::Left, 10.0));
+        table.get(&state, state.zobrist(), 3, 0.5);
+        table.get(&state, state.zobrist(), 4, 0.5);
+        table.get(&state, state.zobrist(), 3, 0.75);
+        table.get(&board(1), board(1).zobrist(), 1, 1.0);
+        table.next_generation();
+
+        let stats = table.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 3));
+        assert_eq!((stats.shallow_rejections, stats.prob_rejections), (1, 1));
+        assert_eq!(stats.inserts_by_depth, vec![0, 0, 0, 1]);
+        assert_eq!((stats.size, stats.peak_size), (1, 1));
+        assert_eq!(stats.hit_rate(), 0.25);
+
+        let mut total = TranspositionStats::default();
+        total += &stats;
+        total += &stats;
+        assert_eq!(total.lookups(), 8);
+        assert_eq!(total.inserts_by_depth, vec![0, 0, 0, 2]);
+        assert_eq!((total.size, total.peak_size), (2, 1));
+
+        // Replacing an entry counts a second insert at its depth
+        table.insert(state, state.zobrist(), 3, 0.5, (Direction::Up, 12.0));
+        let stats = table.stats();
+        assert_eq!(stats.inserts_by_depth, vec![0, 0, 0, 2]);
+        assert_eq!(stats.size, 1);
+
+        table.clear();
+        assert_eq!(table.stats(), TranspositionStats::default());
+    }
+
+    #[test]
+    fn test_replacement() {
+        let states = colliding(2, 3);
+        let (a, b, c) = (states[0], states[1], states[2]);
+
+        let always = &mut FixedTranspositionTable::new(0, Replacement::AlwaysReplace);
+        always.insert(a, a.zobrist(), 5, 1.0, (Direction::Left, 1.0));
+        always.insert(b, b.zobrist(), 1, 1.0, (Direction::Up, 2.0));
+        assert_eq!(always.get(&a, a.zobrist(), 1, 1.0), None);
+        assert_eq!(
+            always.get(&b, b.zobrist(), 1, 1.0),
+            Some((Direction::Up, 2.0))
+        );
+
+        let deep = &mut FixedTranspositionTable::new(0, Replacement::DepthPreferred);
+        deep.insert(a, a.zobrist(), 5, 1.0, (Direction::Left, 1.0));
+        deep.insert(b, b.zobrist(), 1, 1.0, (Direction::Up, 2.0));
+        assert_eq!(
+            deep.get(&a, a.zobrist(), 5, 1.0),
+            Some((Direction::Left, 1.0))
+        );
+        assert_eq!(deep.get(&b, b.zobrist(), 1, 1.0), None);
+        // Once the search is over the deep result can be replaced
+        deep.next_generation();
+        deep.insert(b, b.zobrist(), 1, 1.0, (Direction::Up, 2.0));
+        assert_eq!(
+            deep.get(&b, b.zobrist(), 1, 1.0),
+            Some((Direction::Up, 2.0))
+        );
+
+        let two_tier = &mut FixedTranspositionTable::new(0, Replacement::TwoTier);
+        two_tier.insert(a, a.zobrist(), 5, 1.0, (Direction::Left, 1.0));
+        two_tier.insert(b, b.zobrist(), 1, 1.0, (Direction::Up, 2.0));
+        two_tier.insert(c, c.zobrist(), 2, 1.0, (Direction::Down, 3.0));
+        assert_eq!(
+            two_tier.get(&a, a.zobrist(), 5, 1.0),
+            Some((Direction::Left, 1.0))
+        );
+        assert_eq!(two_tier.get(&b, b.zobrist(), 1, 1.0), None);
+        assert_eq!(
+            two_tier.get(&c, c.zobrist(), 2, 1.0),
+            Some((Direction::Down, 3.0))
+        );
+        // A deeper result takes the deep slot and pushes the old one down
+        two_tier.insert(b, b.zobrist(), 6, 1.0, (Direction::Up, 4.0));
+        assert_eq!(
+            two_tier.get(&b, b.zobrist(), 6, 1.0),
+            Some((Direction::Up, 4.0))
+        );
+        assert_eq!(
+            two_tier.get(&a, a.zobrist(), 5, 1.0),
+            Some((Direction::Left, 1.0))
+        );
+        assert_eq!(two_tier.get(&c, c.zobrist(), 2, 1.0), None);
+    }
+}
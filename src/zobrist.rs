@@ -0,0 +1,146 @@
+use std::hash::{BuildHasherDefault, Hasher};
+use std::sync::OnceLock;
+
+use super::board::Board;
+use super::game::State;
+
+/*
+ZOBRIST KEYS
+*/
+
+// NOTE: A board's key is the XOR of one random key per occupied (cell, value)
+// pair, cells numbered y * SIZE + x. Empty cells contribute nothing, so a spawn
+// is a single XOR and a move only touches the rows it changed.
+
+const ZOBRIST_SEED: u64 = 0x2048_2048_2048_2048;
+
+fn splitmix64(mut value: u64) -> u64 {
+    value = value.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    value = (value ^ (value >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    value ^ (value >> 31)
+}
+
+pub fn cell_key(cell: usize, value: u16) -> u64 {
+    if value == 0 {
+        return 0;
+    }
+    splitmix64(ZOBRIST_SEED ^ ((cell as u64) << 16 | value as u64))
+}
+
+// Key of the packed 16-bit row at each y, indexed [row][y]
+static ROW_KEYS: OnceLock<Vec<[u64; 4]>> = OnceLock::new();
+
+fn row_keys() -> &'static [[u64; 4]] {
+    ROW_KEYS.get_or_init(|| {
+        (0..1 << 16)
+            .map(|row: usize| {
+                let mut keys = [0; 4];
+                for (y, key) in keys.iter_mut().enumerate() {
+                    for x in 0..4 {
+                        let value = (row >> (12 - 4 * x)) & 0xF;
+                        *key ^= cell_key(4 * y + x, value as u16);
+                    }
+                }
+                keys
+            })
+            .collect()
+    })
+}
+
+// Same as the generic Board::zobrist, four lookups instead of 16 hashes
+pub fn state_zobrist(state: State) -> u64 {
+    let keys = row_keys();
+    let rows = state.rows();
+    (0..4).fold(0, |key, y| key ^ keys[rows[y] as usize][y])
+}
+
+// Updates key for rows that differ between state and next_state
+pub fn state_zobrist_after(key: u64, state: State, next_state: State) -> u64 {
+    let keys = row_keys();
+    let (rows, next_rows) = (state.rows(), next_state.rows());
+    let mut key = key;
+    for y in 0..4 {
+        if rows[y] != next_rows[y] {
+            key ^= keys[rows[y] as usize][y] ^ keys[next_rows[y] as usize][y];
+        }
+    }
+    key
+}
+
+// Key after value spawns in the empty cell (x, y)
+pub fn zobrist_spawn<B: Board>(key: u64, x: u16, y: u16, value: u16) -> u64 {
+    key ^ cell_key((y * B::SIZE + x) as usize, value)
+}
+
+/*
+KEY HASHING
+*/
+
+// Zobrist keys are already uniformly random, so maps keyed by them can use the
+// key as its own hash instead of running SipHash
+#[derive(Default)]
+pub struct ZobristHasher {
+    hash: u64,
+}
+
+impl Hasher for ZobristHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.hash = (self.hash << 8) | byte as u64;
+        }
+    }
+
+    fn write_u64(&mut self, value: u64) {
+        self.hash = value;
+    }
+
+    fn finish(&self) -> u64 {
+        self.hash
+    }
+}
+
+pub type ZobristBuildHasher = BuildHasherDefault<ZobristHasher>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::Grid;
+    use crate::game::{Direction, Symmetry};
+    use crate::precompute::move_state;
+
+    #[test]
+    fn test_state_zobrist() {
+        let state = State::from_grid([[1, 2, 0, 0], [0, 0, 3, 0], [15, 0, 0, 0], [0, 0, 0, 1]]);
+        assert_eq!(state_zobrist(state), Board::zobrist(&state));
+        assert_eq!(state_zobrist(State { board: 0 }), 0);
+        assert_ne!(
+            state_zobrist(state),
+            state_zobrist(state.transform(Symmetry::FlipHorizontal))
+        );
+
+        for direction in [
+            Direction::Left,
+            Direction::Right,
+            Direction::Up,
+            Direction::Down,
+        ] {
+            let moved = move_state(state, direction);
+            let key = state_zobrist_after(state_zobrist(state), state, moved);
+            assert_eq!(key, state_zobrist(moved));
+        }
+    }
+
+    #[test]
+    fn test_zobrist_spawn() {
+        let mut state = State::from_grid([[1, 2, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0]]);
+        let key = zobrist_spawn::<State>(state_zobrist(state), 3, 2, 2);
+        state.set_tile(3, 2, 2);
+        assert_eq!(key, state_zobrist(state));
+
+        let mut grid = Grid::<3>::from_grid([[1, 0, 0], [0, 0, 0], [0, 0, 4]]);
+        let key = zobrist_spawn::<Grid<3>>(grid.zobrist(), 1, 2, 1);
+        grid.set_tile(1, 2, 1);
+        assert_eq!(key, grid.zobrist());
+    }
+}